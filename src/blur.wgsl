@@ -0,0 +1,26 @@
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) color: vec4<f32>,
+}
+
+@group(1) @binding(0)
+var t: texture_2d<f32>;
+
+@group(1) @binding(1)
+var s: sampler;
+
+// 5x5 box blur over every other texel, darkened a bit so the menu stays readable
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let texel = 2.0 / vec2<f32>(textureDimensions(t));
+    var sum = vec4<f32>(0.0);
+
+    for (var x = -2; x <= 2; x += 1) {
+        for (var y = -2; y <= 2; y += 1) {
+            sum += textureSample(t, s, in.uv + vec2<f32>(f32(x), f32(y)) * texel);
+        }
+    }
+
+    return vec4<f32>(sum.rgb / 25.0 * 0.6, 1.0) * in.color;
+}
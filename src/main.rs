@@ -1,21 +1,69 @@
+use ggez::audio::SoundSource;
+use ggez::input::gamepad::{gilrs, GamepadId};
 use ggez::{graphics::*, input::keyboard::KeyCode, *};
 use mint::Point2;
-use std::collections::VecDeque;
+use notify::Watcher;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::f32::consts::{PI, TAU};
+use std::rc::Rc;
+use std::sync::mpsc;
 
 use touhoulang::*;
 use touhoulang_macro::Evaluate;
 
-type Story = Vec<StoryLine>;
+mod physics;
+use physics::*;
+
 type UIMenu = VecDeque<UISelectable<Text>>;
 
 // ------------------------------------------
 // Entities
 // ------------------------------------------
 
+struct PowerUp {
+    body: Body,
+    kind: PowerUpKind,
+}
+
+// Power adds bullets to the player's pool, Bomb adds a bomb and Heal restores full health
+#[derive(Clone, Copy)]
+enum PowerUpKind {
+    Power,
+    Bomb,
+    Heal,
+}
+
+// A wave only spawns once every enemy on screen is dead, then waits its spawn delay
+struct Wave {
+    enemies: Vec<Enemy>,
+    spawn_delay: f32,
+}
+
 struct Player {
     health: Health,
     body: Body,
     spell: Spell,
+
+    focus_speed: f32,
+    is_focused: bool,
+    hitbox: f32,
+
+    lives: u32,
+    max_lives: u32,
+    bombs: u32,
+    spawn: Point2<f32>,
+    explosion: Explosion,
+    // Velocity from the last hit, wearing off over KNOCKBACK_DURATION
+    knockback: Point2<f32>,
+    // Counts the cooldown up between dashes, a dash in progress keeps it at zero
+    dash_timer: Timer,
+    dash_speed: f32,
+    dash_duration: f32,
+    dash_left: f32,
+    // Dashes go the way the player last moved
+    last_dir: Point2<f32>,
 }
 
 struct Enemy {
@@ -24,45 +72,179 @@ struct Enemy {
     spell: Spell,
 
     move_timer: Timer,
-    directions: Vec<f32>,
+    directions: Vec<Point2<f32>>,
+
+    // Health fraction, pattern and fire delay, ordered from the highest threshold
+    phases: Vec<(f32, SpellPattern, f32)>,
+    flash: Option<Timer>,
+    hitbox: f32,
+    explosion: Explosion,
+    // Named bosses get the bar across the top of the screen instead of the floating one
+    boss: Option<String>,
+
+    id: usize,
 }
 
 #[derive(Clone)]
 struct Body {
     sprite: Sprite,
-    position: Point2<f32>,
-    direction: Point2<f32>,
-    speed: f32,
+    rigidbody: Rigidbody,
+    animation: Option<AnimatedSprite>,
 }
 
 struct Health {
     health: u32,
     max_health: u32,
-    on_hit: Option<fn(health: u32)>,
+    // Boxed closures can't borrow State, so they share whatever they touch, see State::player_hit
+    on_hit: Vec<Box<dyn FnMut(u32)>>,
+    invulnerability: Option<Timer>,
+    invincible: bool,
+    iframes: f32,
+    regen_timer: Timer,
+    regen_amount: u32,
+    // Damage multipliers, types missing from the map deal full damage
+    resistances: HashMap<DamageType, f32>,
+    // What resistances shaved off below a whole point, dealt once it adds up to one
+    damage_carry: f32,
+    // Only this type gets through, everything else is shrugged off
+    weakness: Option<DamageType>,
 }
 
 struct Spell {
     bullets: Vec<Bullet>,
     shot_timer: Timer,
+    pattern: SpellPattern,
+    direction: Point2<f32>,
+    // Manual spells only fire a queued shot, the rest fire every time the timer comes around
+    manual: bool,
+    buffered_shot: bool,
+    // Fires every other bullet of each volley, or every other volley when it has a single direction
+    sparse: bool,
+    skipped_volley: bool,
+    // Seconds of warning drawn before each volley, 0 turns it off
+    telegraph: f32,
+    // Where shots leave from, x to the right of the heading and y ahead of it
+    origin_offset: Point2<f32>,
+    // Every direction of the pattern fires this many shots side by side, fanned out over the spread
+    shot_count: usize,
+    shot_spread: f32,
+}
+
+#[derive(Clone, Copy)]
+enum SpellPattern {
+    Straight,
+    Aimed,
+    Circle { count: usize },
+    Fan { count: usize, spread: f32 },
 }
 
 #[derive(Clone)]
 struct Bullet {
     body: Body,
     is_visible: bool,
+    turn_rate: f32,
+    lifetime: Option<Timer>,
+    grazed: bool,
+    damage_type: DamageType,
+    cancel: BulletCancel,
+    trail: usize,
+    // Speed changes per second along the heading, never dropping below a standstill
+    acceleration: f32,
+    max_speed: Option<f32>,
+    // Pooled bullets start every shot back at this speed
+    launch_speed: f32,
+    wave: Option<BulletWave>,
+    // Seconds since the bullet was fired
+    elapsed: f32,
+    // Piercing bullets skip the enemy they just went through instead of hitting it every frame
+    last_hit: Option<usize>,
+}
+
+// Sways the bullet side to side across its heading, frequency being in radians per second
+#[derive(Clone, Copy)]
+struct BulletWave {
+    amplitude: f32,
+    frequency: f32,
+}
+
+// Whether a player shot wipes out enemy bullets it touches, and if it survives doing so
+#[derive(Clone, Copy, PartialEq)]
+enum BulletCancel {
+    Off,
+    EnemyOnly,
+    Both,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum DamageType {
+    Normal,
+    Piercing,
 }
 
 #[derive(Clone)]
 struct Sprite {
-    image: Image,
+    // Left out where there's no graphics context, like in tests, and drawn as nothing
+    image: Option<Image>,
     color: Color,
+    // Size the image is drawn at, so art of any resolution fits without touching draw code
+    scale: f32,
+}
+
+// Sheets are a grid of `frames` columns; rows are idle, banking left and banking right
+#[derive(Clone)]
+struct AnimatedSprite {
+    sheet: Image,
+    frames: usize,
+    rows: usize,
+    frame: usize,
+    row: usize,
+    frame_timer: Timer,
 }
 
+#[derive(Clone)]
 struct Timer {
     time: std::time::Duration,
     delay: f32,
 }
 
+// Shared by everything stepped once per combat frame, targets being whatever it can hit
+trait Updatable {
+    type Targets: ?Sized;
+
+    fn update(&mut self, world: &mut UpdateCtx, targets: &mut Self::Targets);
+}
+
+// Drawn back to front in this order
+#[derive(Clone, Copy, PartialEq)]
+enum Layer {
+    Background,
+    Entities,
+    Bullets,
+    Player,
+    PlayerBullets,
+    // The focus dot and cooldown bar, kept above the player's own shots
+    PlayerMarkers,
+    Particles,
+    Hitboxes,
+    Hud,
+    Overlay,
+}
+
+trait Renderable {
+    fn layer(&self) -> Layer;
+    fn render(&self, ctx: &Context, canvas: &mut Canvas, palette: Palette);
+}
+
+struct UpdateCtx<'a> {
+    ctx: &'a Context,
+    dt: f32,
+    screen: &'a Screen,
+    sounds: &'a mut SoundBank,
+    graze: &'a mut u32,
+    // Where each landed hit was and how much it took off, for the combo and damage popups
+    hits: &'a mut Vec<(Point2<f32>, u32)>,
+}
+
 // ------------------------------------------
 // UI
 // ------------------------------------------
@@ -72,18 +254,84 @@ struct Particle {
     timer: Timer,
 }
 
+// Floats up from where a hit landed and fades out over its timer
+struct DamagePopup {
+    text: Text,
+    pos: Point2<f32>,
+    timer: Timer,
+    velocity: Point2<f32>,
+}
+
+// Landing hits builds the multiplier up, letting the timer run out knocks it back down a step
+struct Combo {
+    multiplier: u32,
+    timer: Timer,
+}
+
+struct ScreenShake {
+    magnitude: f32,
+    timer: Timer,
+    offset: Point2<f32>,
+}
+
+// Colors for gameplay elements only, menus and the background look the same in every palette
+#[derive(Clone, Copy, Default, PartialEq)]
+enum Palette {
+    #[default]
+    Default,
+    Deuteranopia,
+    Protanopia,
+}
+
+// Particle count, lifetime and speed of the burst left behind on death
+type Explosion = (usize, f32, f32);
+
+// Enemy scaling per tier: bullet speed, delay between shots, health
+// Easy:   x0.75, x1.5, x0.75
+// Normal: x1.0,  x1.0, x1.0
+// Hard:   x1.25, x0.7, x1.5
+#[derive(Clone, Copy, Default)]
+enum Difficulty {
+    Easy,
+    #[default]
+    Normal,
+    Hard,
+}
+
 #[derive(PartialEq)]
 enum GameState {
+    Title,
     Combat,
     Paused,
     Cinematic,
+    // The run has ended, combat stops and the result waits behind the retry menu
+    GameOver,
+    Victory,
+}
+
+// Lines play in order from the cursor, choices and jumps move it to a labelled line instead
+struct Story {
+    lines: Vec<StoryLine>,
+    labels: HashMap<String, usize>,
+    cursor: usize,
 }
 
 struct StoryLine {
     text: Text,
+    // Narration when there's no one to name
+    speaker: Option<String>,
+    label: Option<String>,
+    // Shown as a menu once the line is read, each one jumps to its label
+    choices: Vec<(String, String)>,
+    // Where to go after this line, for branches that skip past the others
+    next: Option<String>,
     sprite: Sprite,
     pos: Point2<f32>,
     color: Color,
+
+    content: String,
+    revealed_chars: usize,
+    reveal_timer: Timer,
 }
 
 struct UISelectable<T: Drawable> {
@@ -91,7 +339,104 @@ struct UISelectable<T: Drawable> {
     pos: Point2<f32>,
     color: Color,
     select_color: Color,
-    action: fn(&mut Context, &mut State),
+    action: UIAction,
+}
+
+#[derive(Clone, Copy)]
+enum UIAction {
+    Button(fn(&mut Context, &mut State)),
+    // Picks the story line's choice at this index
+    Choice(usize),
+    Slider {
+        value: fn(&State) -> f32,
+        on_change: fn(&mut State, f32),
+    },
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct KeyBindings {
+    up: KeyCode,
+    down: KeyCode,
+    left: KeyCode,
+    right: KeyCode,
+    focus: KeyCode,
+    pause: KeyCode,
+    skip: KeyCode,
+    bomb: KeyCode,
+    shoot: KeyCode,
+    fast_forward: KeyCode,
+    dash: KeyCode,
+}
+
+#[derive(Clone, Copy)]
+enum Binding {
+    Up,
+    Down,
+    Left,
+    Right,
+    Focus,
+    Pause,
+    Skip,
+    Bomb,
+    Shoot,
+    FastForward,
+    Dash,
+}
+
+// Everything the player controls during one combat update, so runs can be recorded and replayed
+#[derive(Clone, Copy, Default)]
+struct InputFrame {
+    dir: [f32; 2],
+    focus: bool,
+    bomb: bool,
+    shoot: bool,
+    dash: bool,
+    // Pausing and the assist toggle happen outside combat, so the frame after them carries them
+    resumed: bool,
+    assist: bool,
+}
+
+// Text file: a `difficulty <name>` line, a `seed <number>` line, an `assist <bool>` line,
+// a `script <line count>` line followed by that many script lines, then one
+// `<dx> <dy> <focus> <bomb> <shoot> <dash> <resumed> <assist>` line per combat update
+struct Replay {
+    difficulty: Difficulty,
+    seed: u64,
+    assist: bool,
+    script: String,
+    frames: Vec<InputFrame>,
+}
+
+// Best runs first, stored as one `<name> <score>` line each in the user data directory
+struct HighScores {
+    entries: Vec<(String, u32)>,
+}
+
+enum Sound {
+    Shoot,
+    Hit,
+    Death,
+}
+
+struct SoundBank {
+    shoot: Option<audio::Source>,
+    hit: Option<audio::Source>,
+    death: Option<audio::Source>,
+    volume: f32,
+}
+
+// Images stay loaded across restarts, so hot reloading a script doesn't read them from disk again
+#[derive(Default)]
+struct AssetCache {
+    images: HashMap<String, Image>,
+}
+
+// Watches the script's folder rather than the file, since editors often save by swapping in a new file
+struct ScriptWatcher {
+    _watcher: Option<notify::RecommendedWatcher>,
+    events: mpsc::Receiver<notify::Result<notify::Event>>,
+    file_name: Option<std::ffi::OsString>,
+    last_event: Option<std::time::Instant>,
 }
 
 struct Screen {
@@ -101,47 +446,273 @@ struct Screen {
 
 struct State {
     uis: VecDeque<UIMenu>,
-    last_update: std::time::SystemTime,
+    sounds: SoundBank,
+    assets: AssetCache,
+    bindings: KeyBindings,
+    watcher: ScriptWatcher,
+    script_path: String,
 
     gamestate: GameState,
     story: Story,
+    // How long a finished line stays up while fast-forwarding
+    fast_forward_timer: Timer,
 
     screen: Screen,
+    window: Screen,
     background: Image,
+    bg_offset: f32,
+    clear_color: Color,
+    music: Option<audio::Source>,
+    script: Option<Globals>,
 
     player: Option<Player>,
-    enemy: Option<Enemy>,
+    enemies: Vec<Enemy>,
+    waves: VecDeque<Wave>,
+    wave_timer: Option<Timer>,
 
-    texts: Vec<Text>,
     particles: Vec<Particle>,
+    popups: Vec<DamagePopup>,
+    particle_sprite: Sprite,
+    power_ups: Vec<PowerUp>,
+    shake: Option<ScreenShake>,
+    player_hit: Rc<Cell<bool>>,
+    // Whether that hit left the player a single point from death
+    player_near_death: Rc<Cell<bool>>,
+
+    pauses_remaining: u32,
+    continues: u32,
+    debug_overlay: bool,
+    // Drawn on their own so they can be on without the stats
+    show_hitboxes: bool,
+    radar: bool,
+    fullscreen: bool,
+    graze: u32,
+    score: u32,
+    combo: Combo,
+    practice_phase: usize,
+    difficulty: Difficulty,
+    palette: Palette,
+    // Pausing blurs an offscreen copy of the frame when the shader could be built
+    pause_blur: bool,
+    // Holds the shoot key down for players who'd rather not, recorded like a real press
+    auto_fire: bool,
+    // Thins out and slows down enemy volleys for anyone finding them too much
+    assist: bool,
+    blur_shader: Option<Shader>,
+    scene: ScreenImage,
+
+    script_text: String,
+    seed: u64,
+    rng: StdRng,
+    bomb_queued: bool,
+    resume_queued: bool,
+    step_time: f32,
+    time_scale: f32,
+    recording: Vec<InputFrame>,
+    // A hot reload mid-run changes the fight, so the frames after it aren't recorded
+    recording_stopped: bool,
+    playback: Option<VecDeque<InputFrame>>,
+
+    high_scores: HighScores,
+    name_entry: Option<String>,
+    // Only a fresh key press lets its character through, so key repeat and keys still held
+    // from the run don't type into the name
+    name_key_fresh: bool,
+    // Set while the Yes/No dialog is up, and once Yes lets the window close
+    confirming_quit: bool,
+    quit_confirmed: bool,
+    // Closing the window mid-run holds the game without spending a pause, No picks it back up
+    quit_interrupted: Option<GameState>,
+    rebinding: Option<Binding>,
 }
 
 // ------------------------------------------
 // Serialization
 // ------------------------------------------
 
-#[derive(Evaluate, Default)]
-struct Globals {
-    background: String,
-    player: InitObject,
-    enemy: InitObject,
+// Declares a script struct along with the names of its fields, which is what the
+// unknown field check compares against
+macro_rules! script_struct {
+    (struct $name:ident { $($field:ident: $ty:ty,)* }) => {
+        #[derive(Evaluate, Default)]
+        struct $name {
+            $($field: $ty,)*
+        }
+
+        impl $name {
+            const FIELDS: &'static [&'static str] = &[$(stringify!($field)),*];
+        }
+    };
+}
+
+script_struct! {
+    struct Globals {
+        title: String,
+        width: f32,
+        height: f32,
+        seed: u32,
+        background: String,
+        bg_scroll: f32,
+        clear_color: String,
+        music: String,
+        player: InitObject,
+        enemies: Vec<InitObject>,
+        waves: Vec<InitWave>,
+        input: InitKeys,
+    }
 }
 
-#[derive(Evaluate, Default)]
-struct InitData {
-    amount: usize,
-    health: u32,
-    speed: f32,
+script_struct! {
+    struct InitData {
+        amount: usize,
+        health: u32,
+        speed: f32,
+        lives: u32,
+        continues: u32,
+        bombs: u32,
+        regen: f32,
+        hitbox: f32,
+        boss: String,
+        particles: usize,
+        particle_ttl: f32,
+        particle_speed: f32,
+        directions: Vec<f32>,
+        patrol: String,
+        spawn: Vec<f32>,
+        move_delay: f32,
+
+        pattern: String,
+        count: usize,
+        spread: f32,
+        turn_rate: f32,
+        lifetime: f32,
+
+        threshold: f32,
+        delay: f32,
+
+        damage_type: String,
+        cancel: String,
+        telegraph: f32,
+        origin_offset: Vec<f32>,
+        trail: usize,
+        acceleration: f32,
+        max_speed: f32,
+        wave_amplitude: f32,
+        wave_frequency: f32,
+        resist_normal: f32,
+        resist_piercing: f32,
+        weakness: String,
+
+        sprite: String,
+        scale: f32,
+        sheet: String,
+        frames: usize,
+        rows: usize,
+        frame_time: f32,
+    }
+}
+
+script_struct! {
+    struct InitKeys {
+        up: String,
+        down: String,
+        left: String,
+        right: String,
+        focus: String,
+        pause: String,
+        skip: String,
+        bomb: String,
+        shoot: String,
+        fast_forward: String,
+        dash: String,
+    }
+}
+
+script_struct! {
+    struct InitWave {
+        enemies: Vec<InitObject>,
+        delay: f32,
+    }
 }
 
-#[derive(Evaluate, Default)]
-struct InitObject {
-    data: InitData,
-    bullet: InitData,
+script_struct! {
+    struct InitObject {
+        data: InitData,
+        bullet: InitData,
+        phases: Vec<InitData>,
+    }
 }
 
-trait Distance {
-    fn distance(&self, other: &Self) -> f32;
+impl Globals {
+    // Enemy ids count through the opening enemies first, then every wave in order
+    fn all_enemies(&self) -> impl Iterator<Item = &InitObject> {
+        self.enemies
+            .iter()
+            .chain(self.waves.iter().flat_map(|wave| wave.enemies.iter()))
+    }
+
+    // Leaving it out or getting it wrong keeps the default backdrop
+    fn clear_color(&self) -> Color {
+        match self.clear_color.as_str() {
+            "" => CLEAR_COLOR,
+            text => parse_color(text).unwrap_or(CLEAR_COLOR),
+        }
+    }
+
+    // Fields left out quietly become 0, these are the ones that leave the game broken when they do
+    fn warnings(&self) -> Vec<String> {
+        let mut warnings = vec![];
+        if !self.clear_color.is_empty() {
+            if let Err(e) = parse_color(&self.clear_color) {
+                warnings.push(format!("the clear_color {e}, the default is used instead"));
+            }
+        }
+        let mut check = |broken: bool, who: &str, what: &str, field: &str| {
+            if broken {
+                warnings.push(format!("{who} {what}, is `{field}` missing?"));
+            }
+        };
+
+        let player = &self.player;
+        check(
+            player.data.health == 0,
+            "the player",
+            "has 0 health",
+            "health",
+        );
+        check(
+            player.data.speed == 0.0,
+            "the player",
+            "can't move",
+            "speed",
+        );
+        check(
+            player.bullet.amount == 0,
+            "the player",
+            "can never shoot",
+            "amount",
+        );
+        check(
+            player.bullet.speed == 0.0,
+            "the player's shots",
+            "never move",
+            "speed",
+        );
+
+        for (i, enemy) in self.all_enemies().enumerate() {
+            let who = format!("enemy {i}");
+            check(enemy.data.health == 0, &who, "dies on spawn", "health");
+            check(enemy.bullet.amount == 0, &who, "can never shoot", "amount");
+            check(
+                enemy.bullet.speed == 0.0,
+                &format!("{who}'s bullets"),
+                "never move",
+                "speed",
+            );
+        }
+
+        warnings
+    }
 }
 
 impl InitObject {
@@ -149,470 +720,4367 @@ impl InitObject {
         self.data.health
     }
 
+    fn lives(&self) -> u32 {
+        self.data.lives
+    }
+
+    fn bombs(&self) -> u32 {
+        self.data.bombs
+    }
+
+    fn continues(&self) -> u32 {
+        self.data.continues
+    }
+
     fn speed(&self) -> f32 {
         self.data.speed
     }
-}
 
-impl Particle {
-    fn new(sprite: &Sprite, ttl: f32, position: [f32; 2], direction: [f32; 2], speed: f32) -> Self {
-        let mut bullet = Bullet::new(sprite, direction, speed);
-        bullet.body.position = Point2::from(position);
-        bullet.is_visible = true;
+    // Scripts that leave the hitbox out keep the engine's default size
+    fn hitbox(&self, default: f32) -> f32 {
+        if self.data.hitbox > 0.0 {
+            self.data.hitbox
+        } else {
+            default
+        }
+    }
 
-        Self {
-            bullet,
-            timer: Timer::new(ttl),
+    // Highest threshold first, the order a fight goes through them
+    fn phases(&self, difficulty: Difficulty) -> Vec<(f32, SpellPattern, f32)> {
+        let mut phases: Vec<_> = self
+            .phases
+            .iter()
+            .map(|phase| {
+                (
+                    phase.threshold / 100.0,
+                    SpellPattern::from_init(phase),
+                    phase.delay * difficulty.shot_delay(),
+                )
+            })
+            .collect();
+        phases.sort_by(|a, b| b.0.total_cmp(&a.0));
+        phases
+    }
+
+    // Each part left out falls back to the default explosion on its own
+    fn explosion(&self) -> Explosion {
+        let or = |value: f32, default: f32| if value > 0.0 { value } else { default };
+        let count = match self.data.particles {
+            0 => DEATH_PARTICLES,
+            count => count,
+        };
+
+        (
+            count,
+            or(self.data.particle_ttl, DEATH_PARTICLE_TTL),
+            or(self.data.particle_speed, DEATH_PARTICLE_SPEED),
+        )
+    }
+
+    // Spawns are fractions of the screen so they land in the same place at any size
+    fn spawn(&self, default: [f32; 2], screen: &Screen) -> [f32; 2] {
+        let [x, y] = match self.data.spawn[..] {
+            [x, y] if x.is_finite() && y.is_finite() => [x, y],
+            _ => default,
+        };
+        [x * screen.width, y * screen.height]
+    }
+
+    // A named patrol wins over the list, leaving both out keeps the default patrol
+    fn directions(&self) -> Option<Vec<Point2<f32>>> {
+        if let Some(directions) = patrol_pattern(&self.data.patrol) {
+            return Some(directions);
+        }
+        if self.data.directions.is_empty() {
+            return None;
         }
+
+        // Steps come in x, y pairs, pairs that aren't finite are dropped
+        Some(
+            self.data
+                .directions
+                .chunks_exact(2)
+                .map(|pair| Point2 {
+                    x: pair[0],
+                    y: pair[1],
+                })
+                .filter(|dir| dir.x.is_finite() && dir.y.is_finite())
+                .collect(),
+        )
     }
+}
 
-    fn update(&mut self, ctx: &Context) {
-        if self.timer.ready(ctx) {
-            self.bullet.is_visible = false;
-        } else {
-            self.bullet.update();
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            up: KeyCode::W,
+            down: KeyCode::S,
+            left: KeyCode::A,
+            right: KeyCode::D,
+            focus: KeyCode::LShift,
+            pause: KeyCode::Escape,
+            skip: KeyCode::Tab,
+            bomb: KeyCode::X,
+            shoot: KeyCode::Z,
+            fast_forward: KeyCode::LControl,
+            dash: KeyCode::C,
         }
     }
 }
 
-impl Health {
-    fn take_damage(&mut self, damage: u32) {
-        self.health = self.health.saturating_sub(damage);
-        if let Some(on_hit) = self.on_hit {
-            on_hit(self.health);
+impl KeyBindings {
+    fn from_init(init: &InitKeys) -> Self {
+        let default = Self::default();
+
+        Self {
+            up: parse_key(&init.up, default.up),
+            down: parse_key(&init.down, default.down),
+            left: parse_key(&init.left, default.left),
+            right: parse_key(&init.right, default.right),
+            focus: parse_key(&init.focus, default.focus),
+            pause: parse_key(&init.pause, default.pause),
+            skip: parse_key(&init.skip, default.skip),
+            bomb: parse_key(&init.bomb, default.bomb),
+            shoot: parse_key(&init.shoot, default.shoot),
+            fast_forward: parse_key(&init.fast_forward, default.fast_forward),
+            dash: parse_key(&init.dash, default.dash),
         }
     }
 
-    fn is_alive(&self) -> bool {
-        self.health > 0
+    fn key(&self, binding: Binding) -> KeyCode {
+        match binding {
+            Binding::Up => self.up,
+            Binding::Down => self.down,
+            Binding::Left => self.left,
+            Binding::Right => self.right,
+            Binding::Focus => self.focus,
+            Binding::Pause => self.pause,
+            Binding::Skip => self.skip,
+            Binding::Bomb => self.bomb,
+            Binding::Shoot => self.shoot,
+            Binding::FastForward => self.fast_forward,
+            Binding::Dash => self.dash,
+        }
     }
 
-    fn percentage(&self) -> f32 {
-        self.health as f32 / self.max_health as f32
+    fn key_mut(&mut self, binding: Binding) -> &mut KeyCode {
+        match binding {
+            Binding::Up => &mut self.up,
+            Binding::Down => &mut self.down,
+            Binding::Left => &mut self.left,
+            Binding::Right => &mut self.right,
+            Binding::Focus => &mut self.focus,
+            Binding::Pause => &mut self.pause,
+            Binding::Skip => &mut self.skip,
+            Binding::Bomb => &mut self.bomb,
+            Binding::Shoot => &mut self.shoot,
+            Binding::FastForward => &mut self.fast_forward,
+            Binding::Dash => &mut self.dash,
+        }
+    }
+
+    fn label(&self, binding: Binding) -> String {
+        format!("{}: {:?}", binding.name(), self.key(binding))
     }
 }
 
-impl Spell {
-    fn new(bullet: Bullet, bullets_size: usize, delay: f32) -> Self {
-        Self {
-            bullets: std::iter::repeat(bullet).take(bullets_size).collect(),
-            shot_timer: Timer::new(delay),
+impl Binding {
+    fn name(self) -> &'static str {
+        match self {
+            Binding::Up => "Up",
+            Binding::Down => "Down",
+            Binding::Left => "Left",
+            Binding::Right => "Right",
+            Binding::Focus => "Focus",
+            Binding::Pause => "Pause",
+            Binding::Skip => "Skip",
+            Binding::Bomb => "Bomb",
+            Binding::Shoot => "Shoot",
+            Binding::FastForward => "Fast-forward",
+            Binding::Dash => "Dash",
         }
     }
+}
 
-    fn spawn(&mut self, ctx: &Context, position: &Point2<f32>) {
-        if self.shot_timer.ready(ctx) {
-            self.bullets
-                .iter_mut()
-                .find(|x| !x.is_visible)
-                .map(|bullet| {
-                    bullet.body.position = *position;
-                    bullet.is_visible = true;
-                });
+impl Palette {
+    fn next(self) -> Self {
+        match self {
+            Palette::Default => Palette::Deuteranopia,
+            Palette::Deuteranopia => Palette::Protanopia,
+            Palette::Protanopia => Palette::Default,
         }
     }
 
-    fn for_each_visible(&self, f: impl FnMut(&Bullet)) {
-        self.bullets.iter().filter(|x| x.is_visible).for_each(f);
+    fn label(self) -> String {
+        let name = match self {
+            Palette::Default => "Default",
+            Palette::Deuteranopia => "Deuteranopia",
+            Palette::Protanopia => "Protanopia",
+        };
+        format!("Palette: {name}")
     }
 
-    fn for_each_visible_mut(&mut self, f: impl FnMut(&mut Bullet)) {
-        self.bullets.iter_mut().filter(|x| x.is_visible).for_each(f);
+    fn player_bullet(self) -> Color {
+        match self {
+            Palette::Default => Color::CYAN,
+            Palette::Deuteranopia => Color::from_rgb(0, 114, 178),
+            Palette::Protanopia => Color::from_rgb(86, 180, 233),
+        }
     }
-}
 
-impl Timer {
-    fn new(delay: f32) -> Self {
-        Self {
-            time: std::time::Duration::new(0, 0),
-            delay,
+    fn enemy_bullet(self) -> Color {
+        match self {
+            Palette::Default => Color::RED,
+            Palette::Deuteranopia => Color::from_rgb(230, 159, 0),
+            Palette::Protanopia => Color::from_rgb(240, 228, 66),
         }
     }
 
-    fn ready(&mut self, ctx: &Context) -> bool {
-        self.time += ctx.time.delta();
-        let ready = self.time.as_secs_f32() > self.delay;
-        if ready {
-            self.time = std::time::Duration::new(0, 0);
+    fn particle(self) -> Color {
+        match self {
+            Palette::Default => Color::MAGENTA,
+            _ => Color::from_rgb(204, 121, 167),
         }
-        ready
     }
-}
 
-impl Body {
-    fn new(sprite: &Sprite, position: [f32; 2], direction: [f32; 2], speed: f32) -> Self {
-        Self {
-            position: Point2::from(position),
-            direction: Point2::from(direction),
-            speed,
-            sprite: sprite.clone(),
+    fn player_health(self) -> Color {
+        match self {
+            Palette::Default => Color::from_rgba(0, 255, 0, 127),
+            Palette::Deuteranopia => Color::from_rgba(86, 180, 233, 127),
+            Palette::Protanopia => Color::from_rgba(0, 114, 178, 127),
         }
     }
-}
 
-impl Bullet {
-    fn new(sprite: &Sprite, direction: [f32; 2], speed: f32) -> Self {
-        Self {
-            body: Body::new(&sprite, [0.0, 0.0], direction, speed),
-            is_visible: false,
+    fn enemy_health(self) -> Color {
+        match self {
+            Palette::Default => Color::from_rgba(255, 0, 0, 127),
+            Palette::Deuteranopia => Color::from_rgba(213, 94, 0, 127),
+            Palette::Protanopia => Color::from_rgba(230, 159, 0, 127),
         }
     }
 
-    fn update(&mut self) {
-        let Point2 { x: dx, y: dy } = self.body.direction;
-        let speed = self.body.speed;
-
-        self.body.position.x += dx * speed;
-        self.body.position.y += dy * speed;
+    fn power_up(self, kind: PowerUpKind) -> Color {
+        match (self, kind) {
+            (Palette::Default, PowerUpKind::Power) => Color::YELLOW,
+            (Palette::Default, PowerUpKind::Bomb) => Color::GREEN,
+            (Palette::Default, PowerUpKind::Heal) => Color::BLUE,
+            (_, PowerUpKind::Power) => Color::from_rgb(240, 228, 66),
+            (_, PowerUpKind::Bomb) => Color::from_rgb(204, 121, 167),
+            (_, PowerUpKind::Heal) => Color::from_rgb(0, 114, 178),
+        }
     }
 
-    fn collided(&self, _other: &Point2<f32>, hitbox_size: f32) -> bool {
-        self.body.position.distance(_other) < hitbox_size
+    // Sprites get the default palette's tints when loaded, drawing swaps them for this one's
+    fn tint(self, color: Color) -> Color {
+        let default = Palette::Default;
+
+        if color == default.player_bullet() {
+            self.player_bullet()
+        } else if color == default.enemy_bullet() {
+            self.enemy_bullet()
+        } else if color == default.particle() {
+            self.particle()
+        } else {
+            color
+        }
     }
 }
 
-impl Player {
-    fn new(sprite: &Sprite, health: u32, bullet: Bullet, bullets_size: usize) -> Self {
-        Self {
-            health: Health {
-                health,
-                max_health: health,
-                on_hit: None,
-            },
-            body: Body::new(sprite, [350.0, 350.0], [0.0, 0.0], 5.0),
-            spell: Spell::new(bullet, bullets_size, 0.1),
+impl Difficulty {
+    fn next(self) -> Self {
+        match self {
+            Difficulty::Easy => Difficulty::Normal,
+            Difficulty::Normal => Difficulty::Hard,
+            Difficulty::Hard => Difficulty::Easy,
         }
     }
 
-    fn update(&mut self, ctx: &Context, enemy: &mut Option<Enemy>) {
-        self.spell.for_each_visible_mut(|bullet| {
-            bullet.update();
+    fn name(self) -> &'static str {
+        match self {
+            Difficulty::Easy => "Easy",
+            Difficulty::Normal => "Normal",
+            Difficulty::Hard => "Hard",
+        }
+    }
 
-            if let Some(enemy) = enemy {
-                if bullet.collided(&enemy.body.position, 100.) {
-                    enemy.health.take_damage(1);
-                    bullet.is_visible = false;
-                }
-            }
+    fn from_name(name: &str) -> Option<Self> {
+        [Difficulty::Easy, Difficulty::Normal, Difficulty::Hard]
+            .into_iter()
+            .find(|difficulty| difficulty.name() == name)
+    }
 
-            if bullet.body.position.x < 0.0 || bullet.body.position.y < 0.0 {
-                bullet.is_visible = false;
-            }
-        });
-        self.spell.spawn(&ctx, &self.body.position);
+    fn label(self) -> String {
+        format!("Difficulty: {}", self.name())
     }
-}
 
-impl Enemy {
-    fn new(sprite: &Sprite, health: u32, speed: f32, bullet: Bullet, bullets_size: usize) -> Self {
-        Self {
-            health: Health {
-                health,
-                max_health: health,
-                on_hit: Some(|hp| println!("Enemy Health: {hp}")),
-            },
-            body: Body::new(sprite, [350.0, 100.0], [1.0, 0.0], speed),
-            spell: Spell::new(bullet, bullets_size, 0.5),
-            directions: vec![-1., 0., 1., 0., 1., 0., -1., 0.],
-            move_timer: Timer::new(1.5),
+    fn bullet_speed(self) -> f32 {
+        match self {
+            Difficulty::Easy => 0.75,
+            Difficulty::Normal => 1.0,
+            Difficulty::Hard => 1.25,
         }
     }
 
-    fn update(&mut self, ctx: &Context, player: &mut Option<Player>, screen: &Screen) {
-        self.move_auto(&ctx);
-        self.spell.for_each_visible_mut(|bullet| {
-            bullet.update();
+    fn shot_delay(self) -> f32 {
+        match self {
+            Difficulty::Easy => 1.5,
+            Difficulty::Normal => 1.0,
+            Difficulty::Hard => 0.7,
+        }
+    }
 
-            if let Some(player) = player {
-                if bullet.collided(&player.body.position, 25.) {
-                    player.health.take_damage(1);
-                    bullet.is_visible = false;
+    fn health(self) -> f32 {
+        match self {
+            Difficulty::Easy => 0.75,
+            Difficulty::Normal => 1.0,
+            Difficulty::Hard => 1.5,
+        }
+    }
+}
+
+impl InputFrame {
+    fn read(ctx: &Context, bindings: &KeyBindings, bomb: bool) -> Self {
+        let mut dir = Point2 { x: 0.0, y: 0.0 };
+
+        for (key, [dx, dy]) in [
+            (bindings.up, DIR_UP),
+            (bindings.down, DIR_DOWN),
+            (bindings.left, DIR_LEFT),
+            (bindings.right, DIR_RIGHT),
+        ] {
+            if ctx.keyboard.is_key_pressed(key) {
+                dir = dir.add(Point2::from([dx, dy]));
+            }
+        }
+
+        let (mut gamepad_focus, mut gamepad_shoot, mut gamepad_dash) = (false, false, false);
+        for (_, gamepad) in ctx.gamepad.gamepads() {
+            for (button, [dx, dy]) in [
+                (gilrs::Button::DPadUp, DIR_UP),
+                (gilrs::Button::DPadDown, DIR_DOWN),
+                (gilrs::Button::DPadLeft, DIR_LEFT),
+                (gilrs::Button::DPadRight, DIR_RIGHT),
+            ] {
+                if gamepad.is_pressed(button) {
+                    dir = dir.add(Point2::from([dx, dy]));
                 }
             }
 
-            if bullet.body.position.x < 0.0 || bullet.body.position.y > screen.height {
-                bullet.is_visible = false;
+            // The stick's Y axis points up, unlike screen coordinates
+            let x = gamepad.value(gilrs::Axis::LeftStickX);
+            let y = -gamepad.value(gilrs::Axis::LeftStickY);
+            if x.hypot(y) > GAMEPAD_DEADZONE {
+                dir = dir.add(Point2 { x, y });
             }
-        });
-        self.spell.spawn(&ctx, &self.body.position);
-    }
 
-    fn move_auto(&mut self, ctx: &Context) {
-        if self.move_timer.ready(ctx) {
-            self.directions.rotate_left(1);
+            gamepad_focus |= gamepad.is_pressed(gilrs::Button::RightTrigger);
+            gamepad_shoot |= gamepad.is_pressed(gilrs::Button::East);
+            gamepad_dash |= gamepad.is_pressed(gilrs::Button::LeftTrigger);
         }
 
-        let vel = self.directions.first().unwrap_or(&0.0);
-
-        self.body.position = Point2 {
-            x: self.body.position.x + vel * self.body.speed,
-            y: self.body.position.y,
+        Self {
+            dir: [dir.x, dir.y],
+            focus: ctx.keyboard.is_key_pressed(bindings.focus) || gamepad_focus,
+            bomb,
+            shoot: ctx.keyboard.is_key_pressed(bindings.shoot) || gamepad_shoot,
+            dash: ctx.keyboard.is_key_pressed(bindings.dash) || gamepad_dash,
+            ..Self::default()
         }
     }
 }
 
-impl StoryLine {
-    fn new(text: &str, sprite: Sprite, pos: [f32; 2], color: Color) -> Self {
-        Self {
-            text: centered_text(text),
-            sprite,
-            pos: pos.into(),
-            color,
+impl Replay {
+    fn save(&self, path: &str) -> std::io::Result<()> {
+        let mut text = format!(
+            "difficulty {}\nseed {}\nassist {}\nscript {}\n{}\n",
+            self.difficulty.name(),
+            self.seed,
+            self.assist,
+            self.script.split('\n').count(),
+            self.script
+        );
+
+        for frame in self.frames.iter() {
+            let [dx, dy] = frame.dir;
+            text += &format!(
+                "{dx} {dy} {} {} {} {} {} {}\n",
+                frame.focus, frame.bomb, frame.shoot, frame.dash, frame.resumed, frame.assist
+            );
         }
+
+        std::fs::write(path, text)
     }
-}
 
-impl Distance for Point2<f32> {
-    fn distance(&self, other: &Self) -> f32 {
-        ((self.x - other.x).powi(2) + (self.y - other.y).powi(2)).sqrt()
+    fn load(path: &str) -> Option<Self> {
+        let text = std::fs::read_to_string(path).ok()?;
+        let mut lines = text.lines().peekable();
+
+        let difficulty = Difficulty::from_name(lines.next()?.strip_prefix("difficulty ")?)?;
+        let seed = lines.next()?.strip_prefix("seed ")?.parse().ok()?;
+        // Replays from before assist mode were all played without it
+        let assist = lines
+            .next_if(|line| line.starts_with("assist "))
+            .map_or(Some(false), |line| {
+                line.strip_prefix("assist ")?.parse().ok()
+            })?;
+        let count: usize = lines.next()?.strip_prefix("script ")?.parse().ok()?;
+        let script = lines.by_ref().take(count).collect::<Vec<_>>().join("\n");
+
+        // Older replays counted script lines without a trailing blank one, which was left behind
+        let frames = lines
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let mut values = line.split_whitespace();
+                Some(InputFrame {
+                    dir: [values.next()?.parse().ok()?, values.next()?.parse().ok()?],
+                    focus: values.next()?.parse().ok()?,
+                    bomb: values.next()?.parse().ok()?,
+                    // Replays from before the shoot key never fire
+                    shoot: values.next().map_or(Some(false), |x| x.parse().ok())?,
+                    dash: values.next().map_or(Some(false), |x| x.parse().ok())?,
+                    // Replays from before these were recorded never paused or toggled assist
+                    resumed: values.next().map_or(Some(false), |x| x.parse().ok())?,
+                    assist: values.next().map_or(Some(assist), |x| x.parse().ok())?,
+                })
+            })
+            .collect::<Option<_>>()?;
+
+        Some(Self {
+            difficulty,
+            seed,
+            assist,
+            script,
+            frames,
+        })
     }
 }
 
-macro_rules! story {
-    ($($spr:ident: $text:expr, $pos:tt,)*) => {{
-        let mut story = vec![$(StoryLine::new($text, $spr, $pos, Color::WHITE)),*];
-        story.reverse();
-        story
-    }};
-    ($($spr:ident: $text:expr, $pos:tt, $color:expr,)*) => {{
-        let mut story = vec![$(StoryLine::new($text, $spr, $pos, $color)),*];
-        story.reverse();
-        story
-    }}
-}
+impl HighScores {
+    fn path(ctx: &Context) -> std::path::PathBuf {
+        ctx.fs.user_data_dir().join(HIGH_SCORES_FILE)
+    }
 
-macro_rules! rect {
-    ($ctx:ident, $w:expr, $h:expr, ($r:literal, $g:literal, $b:literal, $a:literal)) => {
-        Mesh::new_rectangle(
-            $ctx,
-            DrawMode::fill(),
-            Rect::new(0.0, 0.0, $w, $h),
-            Color::from_rgba($r, $g, $b, $a),
+    // A missing or corrupt file just means there are no scores yet
+    fn load(ctx: &Context) -> Self {
+        let text = std::fs::read_to_string(Self::path(ctx)).unwrap_or_default();
+        let mut entries = text
+            .lines()
+            .map(|line| {
+                let (name, score) = line.rsplit_once(' ')?;
+                Some((name.to_owned(), score.parse().ok()?))
+            })
+            .collect::<Option<Vec<(String, u32)>>>()
+            .unwrap_or_default();
+
+        entries.sort_by_key(|&(_, score)| std::cmp::Reverse(score));
+        entries.truncate(MAX_HIGH_SCORES);
+        Self { entries }
+    }
+
+    fn save(&self, ctx: &Context) -> std::io::Result<()> {
+        let path = Self::path(ctx);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let text: String = self
+            .entries
+            .iter()
+            .map(|(name, score)| format!("{name} {score}\n"))
+            .collect();
+        std::fs::write(path, text)
+    }
+
+    fn qualifies(&self, score: u32) -> bool {
+        score > 0
+            && (self.entries.len() < MAX_HIGH_SCORES
+                || self
+                    .entries
+                    .last()
+                    .is_some_and(|&(_, lowest)| score > lowest))
+    }
+
+    fn insert(&mut self, name: String, score: u32) {
+        let rank = self
+            .entries
+            .iter()
+            .position(|&(_, other)| score > other)
+            .unwrap_or(self.entries.len());
+
+        self.entries.insert(rank, (name, score));
+        self.entries.truncate(MAX_HIGH_SCORES);
+    }
+
+    fn table(&self) -> Text {
+        let mut text = String::from("High Scores");
+        for (i, (name, score)) in self.entries.iter().enumerate() {
+            text += &format!("\n{}. {name} {score}", i + 1);
+        }
+        Text::new(text)
+    }
+}
+
+impl AssetCache {
+    // Missing files aren't cached, so adding one while editing shows up on the next reload
+    fn get_or_load(&mut self, ctx: &Context, path: &str) -> Image {
+        if let Some(image) = self.images.get(path) {
+            return image.clone();
+        }
+
+        match Image::from_path(ctx, path) {
+            Ok(image) => {
+                self.images.insert(path.to_owned(), image.clone());
+                image
+            }
+            Err(_) => Image::from_color(ctx, 1, 1, None),
+        }
+    }
+}
+
+impl ScriptWatcher {
+    fn new(path: &str) -> Self {
+        let (sender, events) = mpsc::channel();
+        let path = std::path::Path::new(path);
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => std::path::Path::new("."),
+        };
+
+        let watcher = notify::recommended_watcher(sender).and_then(|mut watcher| {
+            watcher.watch(dir, notify::RecursiveMode::NonRecursive)?;
+            Ok(watcher)
+        });
+
+        if let Err(ref e) = watcher {
+            println!("Couldn't watch {}, hot reload is off: {e}", path.display());
+        }
+
+        Self {
+            _watcher: watcher.ok(),
+            events,
+            file_name: path.file_name().map(|name| name.to_owned()),
+            last_event: None,
+        }
+    }
+
+    // A save can fire a burst of events, so this only reports once they've settled down
+    fn changed(&mut self) -> bool {
+        let touched = self.events.try_iter().flatten().any(|event| {
+            event
+                .paths
+                .iter()
+                .any(|path| path.file_name() == self.file_name.as_deref())
+        });
+
+        if touched {
+            self.last_event = Some(std::time::Instant::now());
+        }
+
+        let settled = self
+            .last_event
+            .is_some_and(|time| time.elapsed().as_secs_f32() >= SCRIPT_RELOAD_DEBOUNCE);
+
+        if settled {
+            self.last_event = None;
+        }
+        settled
+    }
+}
+
+impl SoundBank {
+    fn new(ctx: &Context) -> Self {
+        Self {
+            shoot: load_sound(ctx, SHOOT_SFX_PATH),
+            hit: load_sound(ctx, HIT_SFX_PATH),
+            death: load_sound(ctx, DEATH_SFX_PATH),
+            volume: 1.0,
+        }
+    }
+
+    fn play(&mut self, ctx: &Context, sound: Sound) {
+        let source = match sound {
+            Sound::Shoot => &mut self.shoot,
+            Sound::Hit => &mut self.hit,
+            Sound::Death => &mut self.death,
+        };
+
+        if let Some(source) = source {
+            source.set_volume(self.volume);
+            source.play_detached(ctx).ok();
+        }
+    }
+}
+
+impl Particle {
+    fn new(sprite: &Sprite, ttl: f32, position: [f32; 2], direction: [f32; 2], speed: f32) -> Self {
+        let mut bullet = Bullet::new(sprite, direction, speed);
+        bullet.body.rigidbody.position = Point2::from(position);
+        bullet.is_visible = true;
+
+        Self {
+            bullet,
+            timer: Timer::new(ttl),
+        }
+    }
+
+    // Retires the particle once its ttl runs out, or earlier if it drifts off screen
+    fn advance(&mut self, dt: f32, screen: &Screen) {
+        if self.timer.ready(dt) {
+            self.bullet.is_visible = false;
+        } else {
+            self.bullet.update(dt, screen);
+        }
+    }
+}
+
+impl DamagePopup {
+    fn new(position: Point2<f32>, damage: u32) -> Self {
+        Self {
+            text: Text::new(damage.to_string()),
+            pos: Point2 {
+                x: position.x,
+                y: position.y - DAMAGE_POPUP_OFFSET,
+            },
+            timer: Timer::new(DAMAGE_POPUP_TTL),
+            velocity: Point2 {
+                x: 0.0,
+                y: -DAMAGE_POPUP_SPEED,
+            },
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.timer.progress() >= 1.0
+    }
+}
+
+impl Updatable for DamagePopup {
+    type Targets = ();
+
+    fn update(&mut self, world: &mut UpdateCtx, _: &mut ()) {
+        self.timer.charge(world.dt);
+        self.pos = self.pos.add(self.velocity.scale(world.dt));
+    }
+}
+
+impl Updatable for Particle {
+    type Targets = ();
+
+    fn update(&mut self, world: &mut UpdateCtx, _: &mut ()) {
+        self.advance(world.dt, world.screen);
+    }
+}
+
+impl PowerUp {
+    fn new(sprite: &Sprite, position: [f32; 2], kind: PowerUpKind) -> Self {
+        Self {
+            body: Body::new(
+                &Sprite {
+                    scale: POWER_UP_SCALE,
+                    ..sprite.clone()
+                },
+                position,
+                DIR_DOWN,
+                POWER_UP_SPEED,
+            ),
+            kind,
+        }
+    }
+
+    fn roll(rng: &mut StdRng, sprite: &Sprite, position: [f32; 2]) -> Option<Self> {
+        let kinds = [PowerUpKind::Power, PowerUpKind::Bomb, PowerUpKind::Heal];
+
+        rng.gen_bool(POWER_UP_DROP_CHANCE)
+            .then(|| Self::new(sprite, position, kinds[rng.gen_range(0..kinds.len())]))
+    }
+
+    fn collected_by(&self, player: &Player) -> bool {
+        self.body.position().distance(player.body.position()) < POWER_UP_RADIUS
+    }
+}
+
+impl Updatable for PowerUp {
+    type Targets = ();
+
+    fn update(&mut self, world: &mut UpdateCtx, _: &mut ()) {
+        self.body.move_by(self.body.velocity().scale(world.dt));
+    }
+}
+
+impl PowerUpKind {
+    fn apply(self, player: &mut Player) {
+        match self {
+            PowerUpKind::Power => player.power_up(),
+            PowerUpKind::Bomb => player.bombs = (player.bombs + 1).min(MAX_BOMBS),
+            PowerUpKind::Heal => player.health.health = player.health.max_health,
+        }
+    }
+}
+
+impl Layer {
+    const ALL: [Layer; 10] = [
+        Layer::Background,
+        Layer::Entities,
+        Layer::Bullets,
+        Layer::Player,
+        Layer::PlayerBullets,
+        Layer::PlayerMarkers,
+        Layer::Particles,
+        Layer::Hitboxes,
+        Layer::Hud,
+        Layer::Overlay,
+    ];
+
+    fn shakes(self) -> bool {
+        matches!(
+            self,
+            Layer::Entities
+                | Layer::Bullets
+                | Layer::Player
+                | Layer::PlayerBullets
+                | Layer::PlayerMarkers
+                | Layer::Particles
+                | Layer::Hitboxes
         )
-        .unwrap()
-    };
+    }
+}
+
+impl Combo {
+    fn new() -> Self {
+        Self {
+            multiplier: 1,
+            timer: Timer::new(COMBO_DURATION),
+        }
+    }
+
+    fn hit(&mut self) {
+        self.multiplier = (self.multiplier + COMBO_STEP).min(MAX_COMBO);
+        self.timer.reset();
+    }
+
+    fn update(&mut self, dt: f32) {
+        if self.multiplier > 1 && self.timer.ready(dt) {
+            self.multiplier = self.multiplier.saturating_sub(COMBO_STEP).max(1);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.multiplier = 1;
+        self.timer.reset();
+    }
+}
+
+impl Screen {
+    // The playfield keeps its aspect ratio, so the window shows a bit more on the long side
+    fn viewport(&self, window: &Screen) -> Rect {
+        let scale = (window.width / self.width).min(window.height / self.height);
+        let (w, h) = (window.width / scale, window.height / scale);
+
+        Rect::new((self.width - w) * 0.5, (self.height - h) * 0.5, w, h)
+    }
+
+    // Window pixels to playfield coordinates, for the mouse
+    fn to_logical(&self, window: &Screen, x: f32, y: f32) -> Point2<f32> {
+        let viewport = self.viewport(window);
+        Point2 {
+            x: viewport.x + x * viewport.w / window.width,
+            y: viewport.y + y * viewport.h / window.height,
+        }
+    }
+}
+
+impl ScreenShake {
+    fn new(magnitude: f32) -> Self {
+        Self {
+            magnitude,
+            timer: Timer::new(SHAKE_DURATION),
+            offset: Point2 { x: 0.0, y: 0.0 },
+        }
+    }
+
+    // Jumps to a random offset every update, easing out as the timer runs down
+    fn update(&mut self, rng: &mut StdRng) {
+        let progress = (self.timer.time.as_secs_f32() / self.timer.delay).min(1.0);
+        let strength = self.magnitude * (1.0 - progress).powi(2);
+
+        self.offset = Point2 {
+            x: rng.gen_range(-1.0..=1.0) * strength,
+            y: rng.gen_range(-1.0..=1.0) * strength,
+        };
+    }
+}
+
+impl Health {
+    fn new(health: u32) -> Self {
+        Self {
+            health,
+            max_health: health,
+            on_hit: vec![],
+            invulnerability: None,
+            invincible: false,
+            iframes: 0.0,
+            regen_timer: Timer::new(0.0),
+            regen_amount: 0,
+            resistances: HashMap::new(),
+            damage_carry: 0.0,
+            weakness: None,
+        }
+    }
+
+    // Regenerates in whole points, so slow rates heal 1 point every few seconds
+    fn set_regen(&mut self, per_second: f32) {
+        if per_second > 0.0 {
+            self.regen_amount = per_second.ceil() as u32;
+            self.regen_timer = Timer::new(self.regen_amount as f32 / per_second);
+        } else {
+            self.regen_amount = 0;
+        }
+    }
+
+    fn update(&mut self, dt: f32) {
+        if let Some(ref mut timer) = self.invulnerability {
+            if timer.ready(dt) {
+                self.invulnerability = None;
+            }
+        }
+
+        // Waits out the i-frames after a hit, then a full regen tick before healing again
+        let can_regen = self.regen_amount > 0 && self.is_alive() && !self.is_invulnerable();
+        if can_regen && self.health < self.max_health && self.regen_timer.ready(dt) {
+            self.health = (self.health + self.regen_amount).min(self.max_health);
+        }
+    }
+
+    // Hands back the damage left after resistances, or nothing while invulnerable
+    fn take_damage(&mut self, damage: u32, kind: DamageType) -> Option<u32> {
+        if self.is_invulnerable() {
+            return None;
+        }
+
+        let multiplier = match self.weakness {
+            Some(weakness) if weakness != kind => OFF_WEAKNESS_DAMAGE,
+            _ => self.resistances.get(&kind).copied().unwrap_or(1.0),
+        };
+        let damage = damage as f32 * multiplier + self.damage_carry;
+        self.damage_carry = damage.fract();
+        let damage = damage as u32;
+
+        self.health = self.health.saturating_sub(damage);
+        self.regen_timer.reset();
+
+        for on_hit in self.on_hit.iter_mut() {
+            on_hit(self.health);
+        }
+
+        if self.iframes > 0.0 {
+            self.make_invulnerable(self.iframes);
+        }
+
+        Some(damage)
+    }
+
+    // A resistance of 0.25 takes a quarter off, 1.0 makes the type harmless
+    fn set_resistance(&mut self, kind: DamageType, resistance: f32) {
+        if resistance != 0.0 {
+            self.resistances.insert(kind, (1.0 - resistance).max(0.0));
+        } else {
+            self.resistances.remove(&kind);
+        }
+    }
+
+    fn add_on_hit(&mut self, on_hit: impl FnMut(u32) + 'static) {
+        self.on_hit.push(Box::new(on_hit));
+    }
+
+    fn is_alive(&self) -> bool {
+        self.health > 0
+    }
+
+    fn is_invulnerable(&self) -> bool {
+        self.invincible || self.invulnerability.is_some()
+    }
+
+    fn make_invulnerable(&mut self, duration: f32) {
+        self.invulnerability = Some(Timer::new(duration));
+    }
+
+    fn percentage(&self) -> f32 {
+        self.health as f32 / self.max_health as f32
+    }
+}
+
+impl SpellPattern {
+    fn from_init(init: &InitData) -> Self {
+        match init.pattern.as_str() {
+            "aimed" => SpellPattern::Aimed,
+            "circle" => SpellPattern::Circle { count: init.count },
+            "fan" => SpellPattern::Fan {
+                count: init.count,
+                spread: init.spread.to_radians(),
+            },
+            _ => SpellPattern::Straight,
+        }
+    }
+
+    fn directions(&self, base: Point2<f32>) -> Vec<Point2<f32>> {
+        let at = |offset: f32| base.normalize().rotate(offset);
+
+        match *self {
+            SpellPattern::Straight | SpellPattern::Aimed => vec![base],
+            SpellPattern::Circle { count } => (0..count)
+                .map(|i| at(TAU * i as f32 / count as f32))
+                .collect(),
+            SpellPattern::Fan { count: 0..=1, .. } => vec![base],
+            SpellPattern::Fan { count, spread } => (0..count)
+                .map(|i| at(spread * (i as f32 / (count - 1) as f32 - 0.5)))
+                .collect(),
+        }
+    }
+}
+
+impl InitData {
+    fn origin_offset(&self) -> Point2<f32> {
+        match self.origin_offset[..] {
+            [x, y] => Point2 { x, y },
+            _ => Point2 { x: 0.0, y: 0.0 },
+        }
+    }
+}
+
+impl BulletCancel {
+    fn from_init(init: &InitData) -> Self {
+        match init.cancel.as_str() {
+            "enemy" => BulletCancel::EnemyOnly,
+            "both" => BulletCancel::Both,
+            _ => BulletCancel::Off,
+        }
+    }
+}
+
+impl DamageType {
+    fn from_init(init: &InitData) -> Self {
+        Self::parse(&init.damage_type).unwrap_or(DamageType::Normal)
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "normal" => Some(DamageType::Normal),
+            "piercing" => Some(DamageType::Piercing),
+            _ => None,
+        }
+    }
+
+    // Enemies with a weakness are drawn in the color of the shots that hurt them
+    fn tint(self) -> Color {
+        match self {
+            DamageType::Normal => WEAKNESS_NORMAL_TINT,
+            DamageType::Piercing => WEAKNESS_PIERCING_TINT,
+        }
+    }
 }
 
-macro_rules! draw_at {
-    ($canvas:ident, $ref:expr, ($x:expr, $y:expr)) => {
-        $canvas.draw($ref, DrawParam::default().dest([$x, $y]))
-    };
-    ($canvas:ident, $ref:expr, ($x:expr, $y:expr), $color: expr) => {
-        $canvas.draw($ref, DrawParam::default().dest([$x, $y]).color($color))
-    };
-}
+impl Spell {
+    fn new(bullet: Bullet, bullets_size: usize, delay: f32, pattern: SpellPattern) -> Self {
+        Self {
+            direction: bullet.body.rigidbody.direction,
+            bullets: std::iter::repeat_n(bullet, bullets_size).collect(),
+            shot_timer: Timer::new(delay),
+            pattern,
+            manual: false,
+            buffered_shot: false,
+            sparse: false,
+            skipped_volley: false,
+            telegraph: 0.0,
+            origin_offset: Point2 { x: 0.0, y: 0.0 },
+            shot_count: 1,
+            shot_spread: 0.0,
+        }
+    }
+
+    // Aimed volleys head for the target, or fire straight along the spell's direction without one
+    fn spawn(&mut self, dt: f32, position: &Point2<f32>, target: Option<Point2<f32>>) -> bool {
+        let ready = if self.manual {
+            self.shot_timer.charge(dt);
+            self.buffered_shot && self.shot_timer.progress() >= 1.0
+        } else {
+            self.shot_timer.ready(dt)
+        };
+
+        if !ready {
+            return false;
+        }
+
+        if self.manual {
+            self.buffered_shot = false;
+            self.shot_timer.reset();
+        }
+
+        let base = match (self.pattern, target) {
+            (SpellPattern::Aimed, Some(target)) if target.distance(*position) > 0.0 => {
+                target.sub(*position).normalize()
+            }
+            _ => self.direction,
+        };
+        let directions = self.pattern.directions(base);
+        if self.sparse && directions.len() == 1 {
+            self.skipped_volley = !self.skipped_volley;
+            if self.skipped_volley {
+                return false;
+            }
+        }
+
+        let Point2 { x: side, y: ahead } = self.origin_offset;
+        let right = Point2 {
+            x: -base.y,
+            y: base.x,
+        };
+        let origin = position.add(right.scale(side)).add(base.scale(ahead));
+
+        let count = self.shot_count.max(1);
+        let (spacing, turn) = match count {
+            1 => (0.0, 0.0),
+            _ => (SHOT_SPACING, self.shot_spread / (count - 1) as f32),
+        };
+        let shots: Vec<_> = directions
+            .into_iter()
+            .step_by(if self.sparse { 2 } else { 1 })
+            .flat_map(|direction| {
+                (0..count).map(move |i| {
+                    let lane = i as f32 - (count - 1) as f32 * 0.5;
+                    let across = Point2 {
+                        x: -direction.y,
+                        y: direction.x,
+                    };
+                    (
+                        origin.add(across.scale(spacing * lane)),
+                        direction.rotate(turn * lane),
+                    )
+                })
+            })
+            .collect();
+        let mut fired = false;
+
+        // Skips the rest of the volley once the pool runs out of free bullets
+        for (bullet, (origin, direction)) in
+            self.bullets.iter_mut().filter(|x| !x.is_visible).zip(shots)
+        {
+            bullet.body.rigidbody.position = origin;
+            bullet.body.rigidbody.direction = direction;
+            bullet.body.rigidbody.speed = bullet.launch_speed;
+            bullet.elapsed = 0.0;
+            bullet.is_visible = true;
+            bullet.grazed = false;
+            bullet.last_hit = None;
+
+            if let Some(ref mut lifetime) = bullet.lifetime {
+                lifetime.reset();
+            }
+
+            fired = true;
+        }
+
+        fired
+    }
+
+    // How far into the warning before the next volley, from 0 to 1, or nothing outside of it
+    fn telegraph(&self) -> Option<f32> {
+        if self.manual || self.telegraph <= 0.0 {
+            return None;
+        }
+
+        let window = self.telegraph.min(self.shot_timer.delay);
+        let remaining = self.shot_timer.remaining();
+        (remaining < window).then(|| 1.0 - remaining / window)
+    }
+
+    fn apply_init(&mut self, init: &InitData) {
+        self.pattern = SpellPattern::from_init(init);
+        self.telegraph = init.telegraph;
+        self.origin_offset = init.origin_offset();
+        self.bullets
+            .iter_mut()
+            .for_each(|bullet| bullet.apply_init(init));
+    }
+
+    fn grow(&mut self, amount: usize, max: usize) {
+        let Some(mut bullet) = self.bullets.first().cloned() else {
+            return;
+        };
+
+        bullet.is_visible = false;
+        let amount = amount.min(max.saturating_sub(self.bullets.len()));
+        self.bullets.extend((0..amount).map(|_| bullet.clone()));
+    }
+
+    fn cancel(&mut self, other: &mut Spell) -> u32 {
+        let mut grid = Grid::new(CANCEL_RADIUS * 2.0);
+        for (i, bullet) in other.bullets.iter().enumerate() {
+            if bullet.is_visible {
+                grid.insert(i, bullet.body.position());
+            }
+        }
+
+        let mut cancelled = 0;
+        self.for_each_visible_mut(|shot| {
+            if shot.cancel == BulletCancel::Off {
+                return;
+            }
+
+            let position = shot.body.position();
+            for i in grid.neighbors(position, CANCEL_RADIUS) {
+                let bullet = &mut other.bullets[i];
+
+                if shot.is_visible && bullet.is_visible && bullet.collided(&position, CANCEL_RADIUS)
+                {
+                    bullet.is_visible = false;
+                    shot.is_visible = shot.cancel == BulletCancel::EnemyOnly;
+                    cancelled += 1;
+                }
+            }
+        });
+
+        cancelled
+    }
+
+    fn scale_speed(&mut self, factor: f32) {
+        for bullet in self.bullets.iter_mut() {
+            bullet.body.rigidbody.speed *= factor;
+            bullet.launch_speed *= factor;
+            bullet.acceleration *= factor;
+            bullet.max_speed = bullet.max_speed.map(|max| max * factor);
+        }
+    }
+
+    fn for_each_visible(&self, f: impl FnMut(&Bullet)) {
+        self.bullets.iter().filter(|x| x.is_visible).for_each(f);
+    }
+
+    fn for_each_visible_mut(&mut self, f: impl FnMut(&mut Bullet)) {
+        self.bullets.iter_mut().filter(|x| x.is_visible).for_each(f);
+    }
+}
+
+impl Timer {
+    fn new(delay: f32) -> Self {
+        Self {
+            time: std::time::Duration::new(0, 0),
+            delay,
+        }
+    }
+
+    // Only ever advanced by the fixed logic step, so a timer fires after the same number of steps
+    // no matter the frame rate
+    fn ready(&mut self, dt: f32) -> bool {
+        self.time += std::time::Duration::from_secs_f32(dt);
+        let ready = self.time.as_secs_f32() > self.delay;
+        if ready {
+            self.reset();
+        }
+        ready
+    }
+
+    fn reset(&mut self) {
+        self.time = std::time::Duration::new(0, 0);
+    }
+
+    // Counts up to the delay and waits there instead of starting over
+    fn charge(&mut self, dt: f32) {
+        let full = std::time::Duration::from_secs_f32(self.delay.max(0.0));
+        self.time = (self.time + std::time::Duration::from_secs_f32(dt)).min(full);
+    }
+
+    fn remaining(&self) -> f32 {
+        (self.delay - self.time.as_secs_f32()).max(0.0)
+    }
+
+    // How far along the delay is, from 0 to 1
+    fn progress(&self) -> f32 {
+        if self.delay > 0.0 {
+            (self.time.as_secs_f32() / self.delay).min(1.0)
+        } else {
+            1.0
+        }
+    }
+}
+
+impl Body {
+    fn new(sprite: &Sprite, position: [f32; 2], direction: [f32; 2], speed: f32) -> Self {
+        Self {
+            rigidbody: Rigidbody::new(position, direction, speed),
+            sprite: sprite.clone(),
+            animation: None,
+        }
+    }
+
+    fn animate(&mut self, dt: f32) {
+        if let Some(ref mut animation) = self.animation {
+            animation.update(dt);
+        }
+    }
+
+    fn draw(&self, canvas: &mut Canvas, color: Color) {
+        self.draw_rotated(canvas, self.position(), color, 0.0);
+    }
+
+    fn draw_rotated(
+        &self,
+        canvas: &mut Canvas,
+        position: Point2<f32>,
+        color: Color,
+        rotation: f32,
+    ) {
+        let size = self.sprite.scale;
+        let (image, src) = match (&self.animation, &self.sprite.image) {
+            (Some(animation), _) => (&animation.sheet, animation.src()),
+            (None, Some(image)) => (image, Rect::one()),
+            (None, None) => return,
+        };
+
+        canvas.draw(
+            image,
+            DrawParam::new()
+                .src(src)
+                .dest(position)
+                .scale([size, size])
+                .rotation(rotation)
+                .color(color)
+                .offset([0.5, 0.5]),
+        );
+    }
+}
+
+impl AnimatedSprite {
+    fn from_init(ctx: &Context, assets: &mut AssetCache, init: &InitData) -> Option<Self> {
+        if init.sheet.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            sheet: assets.get_or_load(ctx, &format!("/{}", init.sheet)),
+            frames: init.frames.max(1),
+            rows: init.rows.max(1),
+            frame: 0,
+            row: 0,
+            frame_timer: Timer::new(init.frame_time),
+        })
+    }
+
+    // Advances at most one frame per logic step, like any other Timer
+    fn update(&mut self, dt: f32) {
+        if self.frame_timer.ready(dt) {
+            self.frame = (self.frame + 1) % self.frames;
+        }
+    }
+
+    // Sheets without a row for the requested pose stay on the idle row
+    fn set_row(&mut self, row: usize) {
+        self.row = if row < self.rows { row } else { 0 };
+    }
+
+    fn src(&self) -> Rect {
+        let (w, h) = (1.0 / self.frames as f32, 1.0 / self.rows as f32);
+        Rect::new(self.frame as f32 * w, self.row as f32 * h, w, h)
+    }
+}
+
+impl Movable for Body {
+    fn rigidbody(&self) -> &Rigidbody {
+        &self.rigidbody
+    }
+
+    fn rigidbody_mut(&mut self) -> &mut Rigidbody {
+        &mut self.rigidbody
+    }
+}
+
+impl Sprite {
+    // Scripted art is drawn untinted, the fallback keeps its color
+    fn from_init(
+        ctx: &Context,
+        assets: &mut AssetCache,
+        init: &InitData,
+        fallback: Sprite,
+    ) -> Self {
+        if init.sprite.is_empty() {
+            return fallback.scaled(init);
+        }
+
+        Self {
+            image: Some(assets.get_or_load(ctx, &format!("/{}", init.sprite))),
+            color: Color::WHITE,
+            ..fallback
+        }
+        .scaled(init)
+    }
+
+    // Scripts that leave the scale out keep the sprite's own
+    fn scaled(mut self, init: &InitData) -> Self {
+        if init.scale > 0.0 {
+            self.scale = init.scale;
+        }
+        self
+    }
+}
+
+impl Bullet {
+    // Bullets keep their own tint and turn to face where they're heading
+    fn draw(&self, canvas: &mut Canvas, palette: Palette) {
+        let Point2 { x, y } = self.body.rigidbody.direction;
+        let (color, rotation) = (palette.tint(self.body.sprite.color), y.atan2(x));
+
+        // Trails are faded copies drawn back along the velocity, nothing extra gets simulated
+        let (position, step) = (
+            self.body.position(),
+            self.body.velocity().scale(TRAIL_SPACING),
+        );
+        for i in (1..=self.trail).rev() {
+            let mut ghost = color;
+            ghost.a *= TRAIL_ALPHA * (1.0 - i as f32 / (self.trail + 1) as f32);
+
+            let at = position.sub(step.scale(i as f32));
+            self.body.draw_rotated(canvas, at, ghost, rotation);
+        }
+
+        self.body.draw_rotated(canvas, position, color, rotation);
+    }
+
+    fn new(sprite: &Sprite, direction: [f32; 2], speed: f32) -> Self {
+        Self {
+            body: Body::new(sprite, [0.0, 0.0], direction, speed),
+            is_visible: false,
+            turn_rate: 0.0,
+            lifetime: None,
+            grazed: false,
+            damage_type: DamageType::Normal,
+            cancel: BulletCancel::Off,
+            trail: 0,
+            acceleration: 0.0,
+            max_speed: None,
+            launch_speed: speed,
+            wave: None,
+            elapsed: 0.0,
+            last_hit: None,
+        }
+    }
+
+    fn from_init(sprite: &Sprite, direction: [f32; 2], init: &InitData) -> Self {
+        let mut bullet = Self::new(sprite, direction, init.speed);
+        bullet.apply_init(init);
+        bullet
+    }
+
+    fn apply_init(&mut self, init: &InitData) {
+        self.body.rigidbody.speed = init.speed;
+        self.launch_speed = init.speed;
+        self.acceleration = init.acceleration;
+        self.max_speed = (init.max_speed > 0.0).then_some(init.max_speed);
+        self.wave =
+            (init.wave_amplitude != 0.0 && init.wave_frequency > 0.0).then_some(BulletWave {
+                amplitude: init.wave_amplitude,
+                frequency: init.wave_frequency,
+            });
+        self.turn_rate = init.turn_rate.to_radians();
+        self.lifetime = (init.lifetime > 0.0).then(|| Timer::new(init.lifetime));
+        self.damage_type = DamageType::from_init(init);
+        self.cancel = BulletCancel::from_init(init);
+        self.trail = init.trail.min(MAX_TRAIL_LENGTH);
+    }
+
+    fn update_homing(&mut self, target: &Point2<f32>, dt: f32) {
+        if self.turn_rate <= 0.0 {
+            return;
+        }
+
+        let Point2 { x, y } = self.body.position();
+        let Point2 { x: dx, y: dy } = self.body.rigidbody.direction;
+
+        let current = dy.atan2(dx);
+        let desired = (target.y - y).atan2(target.x - x);
+
+        let max_turn = self.turn_rate * dt;
+        let turn = (desired - current + PI).rem_euclid(TAU) - PI;
+        let angle = current + turn.clamp(-max_turn, max_turn);
+
+        self.body.rigidbody.direction = Point2 {
+            x: angle.cos(),
+            y: angle.sin(),
+        };
+    }
+
+    fn update(&mut self, dt: f32, screen: &Screen) {
+        if self.acceleration != 0.0 {
+            let speed = &mut self.body.rigidbody.speed;
+            *speed = accelerate(*speed, self.acceleration, self.max_speed, dt);
+        }
+        self.body.move_by(self.body.velocity().scale(dt));
+
+        // Only the change in sway is applied, so the bullet stays centered on its straight path
+        let before = self.elapsed;
+        self.elapsed += dt;
+        if let Some(BulletWave {
+            amplitude,
+            frequency,
+        }) = self.wave
+        {
+            let Point2 { x, y } = self.body.rigidbody.direction;
+            let sway = amplitude * ((frequency * self.elapsed).sin() - (frequency * before).sin());
+            self.body.move_by(Point2 { x: -y, y: x }.scale(sway));
+        }
+
+        let expired = self
+            .lifetime
+            .as_mut()
+            .is_some_and(|lifetime| lifetime.ready(dt));
+
+        let Point2 { x, y } = self.body.position();
+        if expired || x < 0.0 || y < 0.0 || x > screen.width || y > screen.height {
+            self.is_visible = false;
+        }
+    }
+
+    fn collided(&self, _other: &Point2<f32>, hitbox_size: f32) -> bool {
+        self.body.position().distance(*_other) < hitbox_size
+    }
+}
+
+// Never drops below a standstill, and never passes the cap when there is one
+fn accelerate(speed: f32, acceleration: f32, max_speed: Option<f32>, dt: f32) -> f32 {
+    let speed = (speed + acceleration * dt).max(0.0);
+    max_speed.map_or(speed, |max| speed.min(max))
+}
+
+impl Player {
+    fn new(
+        sprite: &Sprite,
+        health: u32,
+        speed: f32,
+        lives: u32,
+        bullet: Bullet,
+        bullets_size: usize,
+        spawn: [f32; 2],
+    ) -> Self {
+        // The first dash is ready straight away
+        let mut dash_timer = Timer::new(DASH_COOLDOWN);
+        dash_timer.charge(DASH_COOLDOWN);
+
+        Self {
+            health: Health {
+                iframes: PLAYER_IFRAMES,
+                ..Health::new(health)
+            },
+            body: Body::new(sprite, spawn, [0.0, 0.0], speed),
+            spell: Spell {
+                manual: true,
+                shot_spread: PLAYER_SHOT_SPREAD.to_radians(),
+                ..Spell::new(bullet, bullets_size, 0.1, SpellPattern::Straight)
+            },
+            focus_speed: 120.0,
+            is_focused: false,
+            hitbox: PLAYER_HITBOX,
+            lives,
+            max_lives: lives,
+            bombs: 0,
+            spawn: Point2::from(spawn),
+            explosion: (DEATH_PARTICLES, DEATH_PARTICLE_TTL, DEATH_PARTICLE_SPEED),
+            knockback: Point2 { x: 0.0, y: 0.0 },
+            dash_timer,
+            dash_speed: DASH_SPEED,
+            dash_duration: DASH_DURATION,
+            dash_left: 0.0,
+            last_dir: Point2::from(DIR_UP),
+        }
+    }
+
+    // Each level adds a shot to every volley, and the pool grows with it so just as many volleys
+    // fit on screen. Past the last level it keeps adding bullets like before
+    fn power_up(&mut self) {
+        let spell = &mut self.spell;
+        if spell.shot_count >= MAX_SHOT_COUNT {
+            spell.grow(POWER_UP_BULLETS, MAX_PLAYER_BULLETS * spell.shot_count);
+            return;
+        }
+
+        let volleys = spell.bullets.len() / spell.shot_count.max(1);
+        spell.shot_count += 1;
+        spell.grow(volleys, volleys * spell.shot_count);
+    }
+
+    fn respawn(&mut self) {
+        self.health.health = self.health.max_health;
+        self.health.make_invulnerable(RESPAWN_INVULNERABILITY);
+        self.body.rigidbody.position = self.spawn;
+        self.knockback = Point2 { x: 0.0, y: 0.0 };
+        self.dash_left = 0.0;
+    }
+
+    // Stays on screen like the knockback, and gets the cooldown going once it's over
+    fn apply_dash(&mut self, dt: f32, screen: &Screen) {
+        if self.dash_left <= 0.0 {
+            self.dash_timer.charge(dt);
+            return;
+        }
+
+        let step = dt.min(self.dash_left);
+        self.dash_left -= step;
+        self.dash_timer.reset();
+
+        self.body
+            .move_by(self.last_dir.scale(self.dash_speed * step));
+        let position = &mut self.body.rigidbody.position;
+        position.x = position.x.clamp(0.0, screen.width);
+        position.y = position.y.clamp(0.0, screen.height);
+    }
+
+    // Only the push is kept on screen, regular movement is left as it was
+    fn apply_knockback(&mut self, dt: f32, screen: &Screen) {
+        let speed = self.knockback.length();
+        if speed <= 0.0 {
+            return;
+        }
+
+        self.body.move_by(self.knockback.scale(dt));
+        let position = &mut self.body.rigidbody.position;
+        position.x = position.x.clamp(0.0, screen.width);
+        position.y = position.y.clamp(0.0, screen.height);
+
+        let speed = (speed - KNOCKBACK_IMPULSE * dt / KNOCKBACK_DURATION).max(0.0);
+        self.knockback = self.knockback.normalize().scale(speed);
+    }
+
+    fn handle_input(&mut self, dt: f32, input: &InputFrame) {
+        let dir = Point2::from(input.dir);
+
+        // A press just before the cooldown ends is held onto and fired the moment it does
+        let timer = &self.spell.shot_timer;
+        let remaining = timer.delay * (1.0 - timer.progress());
+        if input.shoot && remaining <= SHOT_BUFFER {
+            self.spell.buffered_shot = true;
+        }
+
+        if dir.length() > 0.0 {
+            self.last_dir = dir.normalize();
+        }
+
+        let cooled_down = self.dash_left <= 0.0 && self.dash_timer.progress() >= 1.0;
+        if input.dash && cooled_down {
+            self.dash_left = self.dash_duration;
+            if !self.health.is_invulnerable() {
+                self.health.make_invulnerable(self.dash_duration);
+            }
+        }
+
+        self.is_focused = input.focus;
+        let speed = if self.is_focused {
+            self.focus_speed
+        } else {
+            self.body.rigidbody.speed
+        };
+
+        if let Some(ref mut animation) = self.body.animation {
+            animation.set_row(match dir.x {
+                x if x < 0.0 => 1,
+                x if x > 0.0 => 2,
+                _ => 0,
+            });
+        }
+
+        self.body.move_by(dir.normalize().scale(speed * dt));
+    }
+}
+
+impl Enemy {
+    fn new(
+        sprite: &Sprite,
+        position: [f32; 2],
+        health: u32,
+        speed: f32,
+        bullet: Bullet,
+        bullets_size: usize,
+        pattern: SpellPattern,
+    ) -> Self {
+        let mut health = Health::new(health);
+        health.add_on_hit(|hp| println!("Enemy Health: {hp}"));
+
+        Self {
+            health,
+            body: Body::new(sprite, position, [1.0, 0.0], speed),
+            spell: Spell::new(bullet, bullets_size, 0.5, pattern),
+            directions: [-1., 0., 1., 0., 1., 0., -1., 0.]
+                .into_iter()
+                .map(|x| Point2 { x, y: 0.0 })
+                .collect(),
+            move_timer: Timer::new(1.5),
+            phases: vec![],
+            flash: None,
+            hitbox: ENEMY_HITBOX,
+            explosion: (DEATH_PARTICLES, DEATH_PARTICLE_TTL, DEATH_PARTICLE_SPEED),
+            boss: None,
+            id: 0,
+        }
+    }
+
+    fn update_phase(&mut self, dt: f32) {
+        if let Some(ref mut flash) = self.flash {
+            if flash.ready(dt) {
+                self.flash = None;
+            }
+        }
+
+        let Some(&(threshold, ..)) = self.phases.first() else {
+            return;
+        };
+
+        if self.health.percentage() <= threshold {
+            self.next_phase();
+        }
+    }
+
+    fn next_phase(&mut self) {
+        let (_, pattern, delay) = self.phases.remove(0);
+        self.spell.pattern = pattern;
+        self.spell.shot_timer.delay = delay;
+        self.spell
+            .bullets
+            .iter_mut()
+            .for_each(|x| x.is_visible = false);
+        self.flash = Some(Timer::new(PHASE_FLASH_DURATION));
+    }
+
+    // Skips ahead to a scripted phase, with health at the top of that phase's band
+    fn set_phase(&mut self, index: usize) {
+        if index >= self.phases.len() {
+            return;
+        }
+
+        self.phases.drain(..index);
+        let threshold = self.phases[0].0;
+        self.health.health = (self.health.max_health as f32 * threshold).floor() as u32;
+        self.next_phase();
+    }
+
+    // Walks through the patrol one step per move_timer tick, an empty patrol stands still
+    fn move_auto(&mut self, dt: f32, screen: &Screen) {
+        if self.move_timer.ready(dt) {
+            self.directions.rotate_left(1);
+        }
+
+        let Some(&vel) = self.directions.first() else {
+            return;
+        };
+        self.body.move_by(vel.scale(self.body.rigidbody.speed * dt));
+
+        let position = &mut self.body.rigidbody.position;
+        position.x = position.x.clamp(0.0, screen.width);
+        position.y = position.y.clamp(0.0, screen.height);
+    }
+}
+
+impl Updatable for Player {
+    type Targets = [Enemy];
+
+    fn update(&mut self, world: &mut UpdateCtx, enemies: &mut [Enemy]) {
+        let dt = world.dt;
+        self.body.animate(dt);
+        self.apply_knockback(dt, world.screen);
+        self.apply_dash(dt, world.screen);
+
+        self.spell.for_each_visible_mut(|bullet| {
+            bullet.update(dt, world.screen);
+
+            if let Some(enemy) = enemies.iter_mut().find(|enemy| {
+                bullet.last_hit != Some(enemy.id)
+                    && bullet.collided(&enemy.body.position(), enemy.hitbox)
+            }) {
+                // Shrugged off hits still stop the bullet, but don't count towards score or combo
+                let damage = enemy.health.take_damage(1, bullet.damage_type);
+                if let Some(damage) = damage.filter(|&damage| damage > 0) {
+                    world.sounds.play(world.ctx, Sound::Hit);
+                    world.hits.push((bullet.body.position(), damage));
+                }
+                bullet.last_hit = Some(enemy.id);
+                bullet.is_visible = bullet.damage_type == DamageType::Piercing;
+            }
+        });
+
+        if self.spell.spawn(dt, &self.body.position(), None) {
+            world.sounds.play(world.ctx, Sound::Shoot);
+        }
+    }
+}
+
+impl Updatable for Enemy {
+    type Targets = Option<Player>;
+
+    fn update(&mut self, world: &mut UpdateCtx, player: &mut Option<Player>) {
+        let dt = world.dt;
+        self.move_auto(dt, world.screen);
+        self.body.animate(dt);
+        self.update_phase(dt);
+
+        self.spell.for_each_visible_mut(|bullet| {
+            if let Some(player) = player {
+                bullet.update_homing(&player.body.position(), dt);
+            }
+            bullet.update(dt, world.screen);
+
+            if let Some(player) = player.as_mut().filter(|p| !p.health.is_invulnerable()) {
+                let position = player.body.position();
+
+                if bullet.collided(&position, player.hitbox) {
+                    if player.health.take_damage(1, bullet.damage_type).is_some() {
+                        world.sounds.play(world.ctx, Sound::Hit);
+
+                        // Hits land only outside i-frames, so the push can't stack up
+                        let away = position.sub(bullet.body.position()).normalize();
+                        player.knockback = away.scale(KNOCKBACK_IMPULSE);
+                    }
+                    bullet.is_visible = bullet.damage_type == DamageType::Piercing;
+                } else if !bullet.grazed && bullet.collided(&position, GRAZE_RADIUS) {
+                    // Each bullet only counts once, no matter how long it lingers nearby
+                    bullet.grazed = true;
+                    *world.graze += 1;
+                }
+            }
+        });
+
+        let target = player.as_ref().map(|player| player.body.position());
+        if self.spell.spawn(dt, &self.body.position(), target) {
+            world.sounds.play(world.ctx, Sound::Shoot);
+        }
+    }
+}
+
+impl StoryLine {
+    fn new(text: &str, speaker: Option<&str>, sprite: Sprite, pos: [f32; 2], color: Color) -> Self {
+        Self {
+            text: centered_text(""),
+            speaker: speaker.map(str::to_owned),
+            label: None,
+            choices: vec![],
+            next: None,
+            sprite,
+            pos: pos.into(),
+            color,
+            content: text.to_owned(),
+            revealed_chars: 0,
+            reveal_timer: Timer::new(STORY_REVEAL_DELAY),
+        }
+    }
+
+    fn update(&mut self, dt: f32) {
+        if !self.is_revealed() && self.reveal_timer.ready(dt) {
+            self.revealed_chars += 1;
+            let shown: String = self.content.chars().take(self.revealed_chars).collect();
+            self.text = centered_text(&shown);
+        }
+    }
+
+    fn is_revealed(&self) -> bool {
+        self.revealed_chars >= self.content.chars().count()
+    }
+
+    fn reveal(&mut self) {
+        self.revealed_chars = self.content.chars().count();
+        self.text = centered_text(&self.content);
+    }
+
+    fn labelled(mut self, label: &str) -> Self {
+        self.label = Some(label.to_owned());
+        self
+    }
+
+    fn choice(mut self, text: &str, label: &str) -> Self {
+        self.choices.push((text.to_owned(), label.to_owned()));
+        self
+    }
+
+    fn then(mut self, label: &str) -> Self {
+        self.next = Some(label.to_owned());
+        self
+    }
+}
+
+impl Story {
+    fn new(lines: Vec<StoryLine>) -> Self {
+        let labels = lines
+            .iter()
+            .enumerate()
+            .filter_map(|(i, line)| Some((line.label.clone()?, i)))
+            .collect();
+
+        Self {
+            lines,
+            labels,
+            cursor: 0,
+        }
+    }
+
+    fn current(&self) -> Option<&StoryLine> {
+        self.lines.get(self.cursor)
+    }
+
+    fn current_mut(&mut self) -> Option<&mut StoryLine> {
+        self.lines.get_mut(self.cursor)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.cursor >= self.lines.len()
+    }
+
+    fn clear(&mut self) {
+        self.cursor = self.lines.len();
+    }
+
+    fn advance(&mut self) {
+        match self.current().and_then(|line| line.next.clone()) {
+            Some(label) => self.jump(&label),
+            None => self.cursor += 1,
+        }
+    }
+
+    // A label nothing carries ends the story
+    fn jump(&mut self, label: &str) {
+        self.cursor = self.labels.get(label).copied().unwrap_or(self.lines.len());
+    }
+}
+
+impl<T: Drawable> UISelectable<T> {
+    // Menu items are drawn centered on their position, relative to the screen center
+    fn bounds(&self, ctx: &Context, screen: &Screen) -> Option<Rect> {
+        let Rect { w, h, .. } = self.img.dimensions(ctx)?;
+        Some(Rect::new(
+            screen.width * 0.5 + self.pos.x - w * 0.5,
+            screen.height * 0.5 + self.pos.y - h * 0.5,
+            w,
+            h,
+        ))
+    }
+}
+
+// `sprite as "Name": ...` labels the line with who's speaking, leaving it out makes it narration
+// Lines can chain `.labelled(..)`, `.choice(..)` and `.then(..)` after their position to branch
+macro_rules! story {
+    ($($spr:ident $(as $speaker:literal)?: $text:expr, $pos:tt $(.$method:ident($($arg:expr),*))*,)*) => {
+        Story::new(vec![$(StoryLine::new(
+            $text,
+            Option::<&str>::None $(.or(Some($speaker)))?,
+            $spr.clone(),
+            $pos,
+            Color::WHITE,
+        )$(.$method($($arg),*))*),*])
+    };
+    ($($spr:ident $(as $speaker:literal)?: $text:expr, $pos:tt, $color:expr,)*) => {
+        Story::new(vec![$(StoryLine::new(
+            $text,
+            Option::<&str>::None $(.or(Some($speaker)))?,
+            $spr.clone(),
+            $pos,
+            $color,
+        )),*])
+    }
+}
+
+macro_rules! rect {
+    ($ctx:ident, $w:expr, $h:expr, ($r:literal, $g:literal, $b:literal, $a:literal)) => {
+        Mesh::new_rectangle(
+            $ctx,
+            DrawMode::fill(),
+            Rect::new(0.0, 0.0, $w, $h),
+            Color::from_rgba($r, $g, $b, $a),
+        )
+        .unwrap()
+    };
+    ($ctx:ident, $w:expr, $h:expr, $color:expr) => {
+        Mesh::new_rectangle($ctx, DrawMode::fill(), Rect::new(0.0, 0.0, $w, $h), $color).unwrap()
+    };
+}
+
+macro_rules! circle {
+    ($ctx:ident, $radius:expr, ($r:literal, $g:literal, $b:literal, $a:literal)) => {
+        Mesh::new_circle(
+            $ctx,
+            DrawMode::fill(),
+            [0.0, 0.0],
+            $radius,
+            0.1,
+            Color::from_rgba($r, $g, $b, $a),
+        )
+        .unwrap()
+    };
+    ($ctx:ident, $radius:expr, $color:expr) => {
+        Mesh::new_circle($ctx, DrawMode::fill(), [0.0, 0.0], $radius, 0.1, $color).unwrap()
+    };
+}
+
+macro_rules! draw_at {
+    ($canvas:ident, $ref:expr, ($x:expr, $y:expr)) => {
+        $canvas.draw($ref, DrawParam::default().dest([$x, $y]))
+    };
+    ($canvas:ident, $ref:expr, ($x:expr, $y:expr), $color: expr) => {
+        $canvas.draw($ref, DrawParam::default().dest([$x, $y]).color($color))
+    };
+}
+
+macro_rules! rebind_button {
+    ($bindings:ident, $binding:expr, $y:expr) => {
+        UISelectable {
+            img: centered_text(&$bindings.label($binding)),
+            pos: Point2 { x: 0., y: $y },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            action: UIAction::Button(|_, state| state.start_rebind($binding)),
+        }
+    };
+}
+
+fn centered_text(text: &str) -> Text {
+    Text::new(TextFragment {
+        text: text.to_owned(),
+        scale: Some(PxScale::from(40.0)),
+        ..Default::default()
+    })
+    .set_layout(TextLayout {
+        h_align: TextAlign::Middle,
+        v_align: TextAlign::Middle,
+    })
+    .to_owned()
+}
+
+fn pause_menu() -> UIMenu {
+    [
+        UISelectable {
+            img: centered_text("Resume"),
+            pos: Point2 { x: 0., y: -150. },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            action: UIAction::Button(|_, state| {
+                state.uis.remove(0);
+                state.resume_queued = true;
+                state.shatter_screen();
+                state.gamestate = if state.story.is_empty() {
+                    GameState::Combat
+                } else {
+                    GameState::Cinematic
+                };
+            }),
+        },
+        UISelectable {
+            img: centered_text("Reset"),
+            pos: Point2 { x: 0., y: -50. },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            action: UIAction::Button(|ctx, state| state.restart(ctx)),
+        },
+        UISelectable {
+            img: centered_text("Options"),
+            pos: Point2 { x: 0., y: 50. },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            action: UIAction::Button(|_, state| {
+                let menu = options_menu(state);
+                state.uis.push_front(menu)
+            }),
+        },
+        UISelectable {
+            img: centered_text("Settings"),
+            pos: Point2 { x: 0., y: 150. },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            action: UIAction::Button(|_, state| {
+                state.uis.push_front(settings_menu(&state.bindings))
+            }),
+        },
+        UISelectable {
+            img: centered_text("Quit"),
+            pos: Point2 { x: 0., y: 250. },
+            color: Color::WHITE,
+            select_color: Color::RED,
+            action: UIAction::Button(|_, state| state.confirm_quit()),
+        },
+    ]
+    .into()
+}
+
+// No comes first, so a stray confirm press keeps the run going
+fn quit_menu() -> UIMenu {
+    [
+        UISelectable {
+            img: centered_text("No"),
+            pos: Point2 { x: 0., y: 100. },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            action: UIAction::Button(|_, state| {
+                state.uis.remove(0);
+                state.confirming_quit = false;
+
+                if let Some(gamestate) = state.quit_interrupted.take() {
+                    state.gamestate = gamestate;
+                    state.resume_queued = true;
+                }
+            }),
+        },
+        UISelectable {
+            img: centered_text("Quit? Yes"),
+            pos: Point2 { x: 0., y: 0. },
+            color: Color::WHITE,
+            select_color: Color::RED,
+            action: UIAction::Button(|ctx, state| {
+                state.quit_confirmed = true;
+                ctx.request_quit();
+            }),
+        },
+    ]
+    .into()
+}
+
+fn run_over_menu() -> UIMenu {
+    [
+        UISelectable {
+            img: centered_text("Retry"),
+            pos: Point2 { x: 0., y: 140. },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            action: UIAction::Button(|ctx, state| state.restart(ctx)),
+        },
+        UISelectable {
+            img: centered_text("Quit"),
+            pos: Point2 { x: 0., y: 210. },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            action: UIAction::Button(|_, state| state.confirm_quit()),
+        },
+    ]
+    .into()
+}
+
+fn continue_menu(continues: u32) -> UIMenu {
+    [
+        UISelectable {
+            img: centered_text(&format!("Continue ({continues} left)")),
+            pos: Point2 { x: 0., y: 0. },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            action: UIAction::Button(|_, state| {
+                state.uis.remove(0);
+                state.on_continue();
+            }),
+        },
+        UISelectable {
+            img: centered_text("Restart"),
+            pos: Point2 { x: 0., y: 100. },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            action: UIAction::Button(|ctx, state| state.restart(ctx)),
+        },
+    ]
+    .into()
+}
+
+fn choice_menu(choices: &[(String, String)]) -> UIMenu {
+    choices
+        .iter()
+        .enumerate()
+        .map(|(i, (text, _))| UISelectable {
+            img: centered_text(text),
+            pos: Point2 {
+                x: 0.,
+                y: -150. + 70. * i as f32,
+            },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            action: UIAction::Choice(i),
+        })
+        .collect()
+}
+
+fn settings_menu(bindings: &KeyBindings) -> UIMenu {
+    [
+        rebind_button!(bindings, Binding::Up, -250.),
+        rebind_button!(bindings, Binding::Down, -205.),
+        rebind_button!(bindings, Binding::Left, -160.),
+        rebind_button!(bindings, Binding::Right, -115.),
+        rebind_button!(bindings, Binding::Focus, -70.),
+        rebind_button!(bindings, Binding::Pause, -25.),
+        rebind_button!(bindings, Binding::Skip, 20.),
+        rebind_button!(bindings, Binding::Bomb, 65.),
+        rebind_button!(bindings, Binding::Shoot, 110.),
+        rebind_button!(bindings, Binding::FastForward, 155.),
+        rebind_button!(bindings, Binding::Dash, 200.),
+        UISelectable {
+            img: centered_text("Back"),
+            pos: Point2 { x: 0., y: 260. },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            action: UIAction::Button(|_, state| {
+                state.uis.pop_front();
+            }),
+        },
+    ]
+    .into()
+}
+
+fn parse_key(name: &str, default: KeyCode) -> KeyCode {
+    match name {
+        "" => default,
+        "A" => KeyCode::A,
+        "B" => KeyCode::B,
+        "C" => KeyCode::C,
+        "D" => KeyCode::D,
+        "E" => KeyCode::E,
+        "F" => KeyCode::F,
+        "G" => KeyCode::G,
+        "H" => KeyCode::H,
+        "I" => KeyCode::I,
+        "J" => KeyCode::J,
+        "K" => KeyCode::K,
+        "L" => KeyCode::L,
+        "M" => KeyCode::M,
+        "N" => KeyCode::N,
+        "O" => KeyCode::O,
+        "P" => KeyCode::P,
+        "Q" => KeyCode::Q,
+        "R" => KeyCode::R,
+        "S" => KeyCode::S,
+        "T" => KeyCode::T,
+        "U" => KeyCode::U,
+        "V" => KeyCode::V,
+        "W" => KeyCode::W,
+        "X" => KeyCode::X,
+        "Y" => KeyCode::Y,
+        "Z" => KeyCode::Z,
+        "0" => KeyCode::Key0,
+        "1" => KeyCode::Key1,
+        "2" => KeyCode::Key2,
+        "3" => KeyCode::Key3,
+        "4" => KeyCode::Key4,
+        "5" => KeyCode::Key5,
+        "6" => KeyCode::Key6,
+        "7" => KeyCode::Key7,
+        "8" => KeyCode::Key8,
+        "9" => KeyCode::Key9,
+        "F1" => KeyCode::F1,
+        "F2" => KeyCode::F2,
+        "F3" => KeyCode::F3,
+        "F4" => KeyCode::F4,
+        "F5" => KeyCode::F5,
+        "F6" => KeyCode::F6,
+        "F7" => KeyCode::F7,
+        "F8" => KeyCode::F8,
+        "F9" => KeyCode::F9,
+        "F10" => KeyCode::F10,
+        "F11" => KeyCode::F11,
+        "F12" => KeyCode::F12,
+        "Up" => KeyCode::Up,
+        "Down" => KeyCode::Down,
+        "Left" => KeyCode::Left,
+        "Right" => KeyCode::Right,
+        "Space" => KeyCode::Space,
+        "Tab" => KeyCode::Tab,
+        "Escape" => KeyCode::Escape,
+        "LShift" => KeyCode::LShift,
+        "RShift" => KeyCode::RShift,
+        "LControl" => KeyCode::LControl,
+        "RControl" => KeyCode::RControl,
+        "LAlt" => KeyCode::LAlt,
+        "RAlt" => KeyCode::RAlt,
+        "Return" | "Enter" => KeyCode::Return,
+        _ => panic!("Unknown key name \"{name}\" in script.th input bindings"),
+    }
+}
+
+fn options_menu(state: &State) -> UIMenu {
+    [
+        UISelectable {
+            img: centered_text("Volume"),
+            pos: Point2 { x: 0., y: -180. },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            action: UIAction::Slider {
+                value: |state| state.sounds.volume,
+                on_change: |state, value| state.sounds.volume = value,
+            },
+        },
+        UISelectable {
+            img: centered_text(&state.difficulty.label()),
+            pos: Point2 { x: 0., y: -110. },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            // Only applies to the next run, enemies already spawned keep their stats
+            action: UIAction::Button(|_, state| {
+                state.difficulty = state.difficulty.next();
+                if let Some(elem) = state.uis[0].front_mut() {
+                    elem.img = centered_text(&state.difficulty.label());
+                }
+            }),
+        },
+        UISelectable {
+            img: centered_text(&state.palette.label()),
+            pos: Point2 { x: 0., y: -50. },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            action: UIAction::Button(|_, state| {
+                state.palette = state.palette.next();
+                if let Some(elem) = state.uis[0].front_mut() {
+                    elem.img = centered_text(&state.palette.label());
+                }
+            }),
+        },
+        UISelectable {
+            img: centered_text(&toggle_label("Pause blur", state.pause_blur)),
+            pos: Point2 { x: 0., y: 10. },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            action: UIAction::Button(|_, state| {
+                state.pause_blur = !state.pause_blur;
+                if let Some(elem) = state.uis[0].front_mut() {
+                    elem.img = centered_text(&toggle_label("Pause blur", state.pause_blur));
+                }
+            }),
+        },
+        UISelectable {
+            img: centered_text(&toggle_label("Auto-fire", state.auto_fire)),
+            pos: Point2 { x: 0., y: 70. },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            action: UIAction::Button(|_, state| {
+                state.auto_fire = !state.auto_fire;
+                if let Some(elem) = state.uis[0].front_mut() {
+                    elem.img = centered_text(&toggle_label("Auto-fire", state.auto_fire));
+                }
+            }),
+        },
+        UISelectable {
+            img: centered_text(&toggle_label("Assist", state.assist)),
+            pos: Point2 { x: 0., y: 130. },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            action: UIAction::Button(|_, state| {
+                state.set_assist(!state.assist);
+                if let Some(elem) = state.uis[0].front_mut() {
+                    elem.img = centered_text(&toggle_label("Assist", state.assist));
+                }
+            }),
+        },
+        UISelectable {
+            img: centered_text("Back"),
+            pos: Point2 { x: 0., y: 210. },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            action: UIAction::Button(|_, state| {
+                state.uis.pop_front();
+            }),
+        },
+    ]
+    .into()
+}
+
+fn toggle_label(name: &str, on: bool) -> String {
+    format!("{name}: {}", if on { "On" } else { "Off" })
+}
+
+fn practice_menu(phase: usize) -> UIMenu {
+    [
+        UISelectable {
+            img: centered_text(&format!("Phase: {}", phase + 1)),
+            pos: Point2 { x: 0., y: -50. },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            action: UIAction::Button(|_, state| {
+                let count = state.practice_phases().max(1);
+                state.practice_phase = (state.practice_phase + 1) % count;
+                if let Some(elem) = state.uis[0].front_mut() {
+                    elem.img = centered_text(&format!("Phase: {}", state.practice_phase + 1));
+                }
+            }),
+        },
+        UISelectable {
+            img: centered_text("Start"),
+            pos: Point2 { x: 0., y: 50. },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            action: UIAction::Button(|ctx, state| state.start_practice(ctx)),
+        },
+        UISelectable {
+            img: centered_text("Back"),
+            pos: Point2 { x: 0., y: 150. },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            action: UIAction::Button(|_, state| {
+                state.uis.pop_front();
+            }),
+        },
+    ]
+    .into()
+}
+
+fn title_menu() -> UIMenu {
+    [
+        UISelectable {
+            img: centered_text("Start"),
+            pos: Point2 { x: 0., y: -50. },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            // Rebuilds the run so the difficulty picked in the options takes effect
+            action: UIAction::Button(|ctx, state| state.restart(ctx)),
+        },
+        UISelectable {
+            img: centered_text("Practice"),
+            pos: Point2 { x: 0., y: 50. },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            action: UIAction::Button(|_, state| {
+                state.uis.push_front(practice_menu(state.practice_phase))
+            }),
+        },
+        UISelectable {
+            img: centered_text("Options"),
+            pos: Point2 { x: 0., y: 150. },
+            color: Color::WHITE,
+            select_color: Color::YELLOW,
+            action: UIAction::Button(|_, state| {
+                let menu = options_menu(state);
+                state.uis.push_front(menu)
+            }),
+        },
+        UISelectable {
+            img: centered_text("Quit"),
+            pos: Point2 { x: 0., y: 250. },
+            color: Color::WHITE,
+            select_color: Color::RED,
+            action: UIAction::Button(|_, state| state.confirm_quit()),
+        },
+    ]
+    .into()
+}
+
+fn describe_script_error(path: &str, script: &str, error: Box<dyn std::any::Any + Send>) -> String {
+    let msg = error
+        .downcast_ref::<String>()
+        .cloned()
+        .or_else(|| error.downcast_ref::<&str>().map(|msg| msg.to_string()))
+        .unwrap_or_default();
+
+    match locate_script_error(script) {
+        Some((line, column)) => format!("{path}:{line}:{column}: {msg}"),
+        None => format!("{path}: {msg}"),
+    }
+}
+
+// Re-evaluates the script one statement at a time to find the first one that fails. The line
+// and column are where that statement starts, since the parser doesn't say which token broke it
+fn locate_script_error(script: &str) -> Option<(usize, usize)> {
+    let ends = script
+        .match_indices('.')
+        .map(|(i, _)| i + 1)
+        .filter(|&end| script[end..].chars().next().is_none_or(char::is_whitespace));
+
+    let mut start = 0;
+    for end in ends {
+        if std::panic::catch_unwind(|| Globals::from_str(&script[..end])).is_err() {
+            let statement = &script[start..end];
+            let offset = start + statement.len() - statement.trim_start().len();
+
+            let line_start = script[..offset].rfind('\n').map_or(0, |i| i + 1);
+            let line = script[..offset].matches('\n').count() + 1;
+            let column = script[line_start..offset].chars().count() + 1;
+
+            return Some((line, column));
+        }
+        start = end;
+    }
+
+    None
+}
+
+// Takes `#RGB`, `#RGBA`, `#RRGGBB`, `#RRGGBBAA` or one of ggez's named colors, in any case
+fn parse_color(text: &str) -> Result<Color, String> {
+    let malformed = || {
+        format!(
+            "`{text}` isn't a color, expected `#RRGGBB` or `#RGB` with optional alpha, \
+             or a name like `white`"
+        )
+    };
+
+    let Some(hex) = text.strip_prefix('#') else {
+        return NAMED_COLORS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(text))
+            .map(|&(_, color)| color)
+            .ok_or_else(malformed);
+    };
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(malformed());
+    }
+
+    // Short forms repeat each digit, so `#f80` is `#ff8800`
+    let width = match hex.len() {
+        3 | 4 => 1,
+        6 | 8 => 2,
+        _ => return Err(malformed()),
+    };
+    let channels: Vec<u8> = (0..hex.len())
+        .step_by(width)
+        .map(|i| u8::from_str_radix(&hex[i..i + width], 16).unwrap_or_default())
+        .map(|value| if width == 1 { value * 17 } else { value })
+        .collect();
+
+    match channels[..] {
+        [r, g, b] => Ok(Color::from_rgb(r, g, b)),
+        [r, g, b, a] => Ok(Color::from_rgba(r, g, b, a)),
+        _ => Err(malformed()),
+    }
+}
+
+fn report_script_warnings(path: &str, script: &str, init: &Globals) {
+    for (line, object, field) in unknown_fields(script) {
+        eprintln!("{path}:{line}: warning: `{object}` has no field `{field}`, it is ignored");
+    }
+    for warning in init.warnings() {
+        eprintln!("{path}: warning: {warning}");
+    }
+}
+
+// The parser quietly drops fields it doesn't know, so typos are caught here instead by
+// looking at every `object's field` and `the field of object` against the known names, and
+// every top-level `the field is` against the script's own
+fn unknown_fields(script: &str) -> Vec<(usize, String, String)> {
+    let mut unknown = vec![];
+
+    for (i, line) in script.lines().enumerate() {
+        // Quoted text can hold anything, like file names or titles
+        let code: String = line.split('"').step_by(2).collect::<Vec<_>>().join(" ");
+        let words: Vec<_> = code
+            .split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '\''))
+            .filter(|word| !word.is_empty())
+            .collect();
+
+        for (j, word) in words.iter().enumerate() {
+            let access = match (word.strip_suffix("'s"), &words[j + 1..]) {
+                (Some(object), [field, ..]) => Some((object, *field, SCRIPT_FIELDS)),
+                (None, [field, "of", object, ..]) if *word == "the" => {
+                    Some((*object, *field, SCRIPT_FIELDS))
+                }
+                (None, [field, "is" | "are", ..]) if *word == "the" => {
+                    Some(("script", *field, &[Globals::FIELDS][..]))
+                }
+                _ => None,
+            };
+
+            if let Some((object, field, _)) = access
+                .filter(|(_, field, known)| !known.iter().any(|fields| fields.contains(field)))
+            {
+                unknown.push((i + 1, object.to_owned(), field.to_owned()));
+            }
+        }
+    }
+
+    unknown
+}
+
+fn read_script(path: &str) -> std::io::Result<String> {
+    std::fs::read_to_string(path).map(|script| strip_comments(&script))
+}
+
+// Drops everything after a `#` outside of quotes, keeping lines in place for error locations
+fn strip_comments(script: &str) -> String {
+    script
+        .lines()
+        .map(|line| {
+            let mut in_string = false;
+            let end = line
+                .char_indices()
+                .find(|&(_, c)| {
+                    in_string ^= c == '"';
+                    c == '#' && !in_string
+                })
+                .map_or(line.len(), |(i, _)| i);
+
+            line[..end].trim_end()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn parse_script(script: &str) -> std::thread::Result<(Globals, KeyBindings)> {
+    std::panic::catch_unwind(|| {
+        let init = Globals::from_str(script);
+        let bindings = KeyBindings::from_init(&init.input);
+        (init, bindings)
+    })
+}
+
+fn check_script(path: &str) -> bool {
+    let script = match read_script(path) {
+        Ok(script) => script,
+        Err(e) => {
+            eprintln!("{path}: {e}");
+            return false;
+        }
+    };
+
+    match parse_script(&script) {
+        Ok((init, _)) => {
+            // Warnings don't fail the check, the game still runs with them
+            report_script_warnings(path, &script, &init);
+            println!("{path}: ok");
+            true
+        }
+        Err(e) => {
+            eprintln!("{}", describe_script_error(path, &script, e));
+            false
+        }
+    }
+}
+
+// Changes that resize bullet pools or the enemy roster can't be patched into a running game
+fn is_structural_change(old: &Globals, new: &Globals) -> bool {
+    old.player.bullet.amount != new.player.bullet.amount
+        || old.enemies.len() != new.enemies.len()
+        || old.waves.len() != new.waves.len()
+        || old
+            .waves
+            .iter()
+            .zip(new.waves.iter())
+            .any(|(old, new)| old.enemies.len() != new.enemies.len())
+        || old
+            .all_enemies()
+            .zip(new.all_enemies())
+            .any(|(old, new)| old.bullet.amount != new.bullet.amount)
+}
+
+// Sampled velocities of a full loop, so each pattern ends where it started
+fn patrol_pattern(name: &str) -> Option<Vec<Point2<f32>>> {
+    let step = |i: usize| TAU * i as f32 / PATROL_STEPS as f32;
+
+    match name {
+        "weave" => Some(
+            (0..PATROL_STEPS)
+                .map(|i| Point2 {
+                    x: if i < PATROL_STEPS / 2 { 1.0 } else { -1.0 },
+                    y: (step(i) * 4.0).cos() * 0.5,
+                })
+                .collect(),
+        ),
+        "figure_eight" => Some(
+            (0..PATROL_STEPS)
+                .map(|i| Point2 {
+                    x: step(i).cos(),
+                    y: (step(i) * 2.0).cos(),
+                })
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+// The window exists before State does, so its title and size are read from the script up front
+fn window_conf(script_path: &str) -> conf::Conf {
+    let init = read_script(script_path)
+        .ok()
+        .and_then(|script| parse_script(&script).ok())
+        .map(|(init, _)| init)
+        .unwrap_or_default();
+
+    let title = if init.title.is_empty() {
+        GAME_TITLE
+    } else {
+        &init.title
+    };
+
+    let mut conf = conf::Conf::new();
+    conf.window_setup = conf.window_setup.title(title);
+    if init.width > 0.0 && init.height > 0.0 {
+        conf.window_mode = conf.window_mode.dimensions(init.width, init.height);
+    }
+    conf
+}
+
+// A zero seed in the script means every run plays out differently
+fn pick_seed(script_seed: u32) -> u64 {
+    if script_seed != 0 {
+        return script_seed as u64;
+    }
+
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos() as u64)
+}
+
+// Spreads the particles evenly around a circle, spin turns the whole ring
+fn burst(
+    sprite: &Sprite,
+    position: [f32; 2],
+    (count, ttl, speed): Explosion,
+    spin: f32,
+) -> Vec<Particle> {
+    (0..count)
+        .map(|i| {
+            let angle = spin + TAU * i as f32 / count as f32;
+            Particle::new(sprite, ttl, position, [angle.cos(), angle.sin()], speed)
+        })
+        .collect()
+}
+
+impl Renderable for Enemy {
+    fn layer(&self) -> Layer {
+        Layer::Entities
+    }
+
+    fn render(&self, ctx: &Context, canvas: &mut Canvas, palette: Palette) {
+        let color = match self.health.weakness {
+            _ if self.flash.is_some() => Color::WHITE,
+            Some(weakness) => weakness.tint(),
+            None => Color::BLACK,
+        };
+
+        self.body.draw(canvas, color);
+
+        // The ring closes in on the enemy as the volley gets closer
+        if let Some(progress) = self.spell.telegraph() {
+            let radius = TELEGRAPH_RADIUS * (1.0 - progress) + TELEGRAPH_MIN_RADIUS;
+            let color = Color {
+                a: progress,
+                ..palette.enemy_bullet()
+            };
+            let ring = Mesh::new_circle(
+                ctx,
+                DrawMode::stroke(TELEGRAPH_WIDTH),
+                [0.0, 0.0],
+                radius,
+                0.1,
+                color,
+            )
+            .unwrap();
+
+            let Point2 { x, y } = self.body.position();
+            draw_at!(canvas, &ring, (x, y));
+        }
+
+        if self.boss.is_some() {
+            return;
+        }
+
+        let healthbar = rect!(
+            ctx,
+            self.health.percentage() * ENEMY_HEALTHBAR_WIDTH,
+            10.0,
+            palette.enemy_health()
+        );
+
+        let Point2 { x, y } = self.body.position();
+        draw_at!(
+            canvas,
+            &healthbar,
+            (x - ENEMY_HEALTHBAR_WIDTH * 0.5, y - ENEMY_HEALTHBAR_OFFSET)
+        );
+    }
+}
+
+impl Renderable for Player {
+    fn layer(&self) -> Layer {
+        Layer::Player
+    }
+
+    fn render(&self, ctx: &Context, canvas: &mut Canvas, _: Palette) {
+        let flash = ctx.time.time_since_start().as_secs_f32() * IFRAMES_FLASH_RATE;
+        let blinking = self.health.invulnerability.is_some();
+        let color = if blinking && (flash as u32).is_multiple_of(2) {
+            Color::new(1.0, 1.0, 1.0, 0.3)
+        } else {
+            Color::WHITE
+        };
+
+        self.body.draw(canvas, color);
+    }
+}
+
+// Enemy bullets go over the enemies but under the player, the player's over the player
+impl Renderable for Spell {
+    fn layer(&self) -> Layer {
+        // Only the player fires by hand
+        if self.manual {
+            Layer::PlayerBullets
+        } else {
+            Layer::Bullets
+        }
+    }
+
+    fn render(&self, _: &Context, canvas: &mut Canvas, palette: Palette) {
+        self.for_each_visible(|bullet| bullet.draw(canvas, palette));
+    }
+}
+
+impl Renderable for Particle {
+    fn layer(&self) -> Layer {
+        Layer::Particles
+    }
+
+    fn render(&self, _: &Context, canvas: &mut Canvas, palette: Palette) {
+        self.bullet.draw(canvas, palette);
+    }
+}
+
+impl Renderable for DamagePopup {
+    fn layer(&self) -> Layer {
+        Layer::Particles
+    }
+
+    fn render(&self, _: &Context, canvas: &mut Canvas, _: Palette) {
+        let Point2 { x, y } = self.pos;
+        let color = Color {
+            a: 1.0 - self.timer.progress(),
+            ..Color::WHITE
+        };
+        draw_at!(canvas, &self.text, (x, y), color);
+    }
+}
+
+impl Renderable for PowerUp {
+    fn layer(&self) -> Layer {
+        Layer::Particles
+    }
+
+    fn render(&self, _: &Context, canvas: &mut Canvas, palette: Palette) {
+        self.body.draw(canvas, palette.power_up(self.kind));
+    }
+}
+
+impl State {
+    fn new(ctx: &Context, difficulty: Difficulty, assets: AssetCache, script_path: String) -> Self {
+        let script_text = read_script(&script_path);
+        Self::from_script(ctx, difficulty, script_text, assets, script_path)
+    }
+
+    // A missing or broken script still starts the game, with the error shown as the story
+    fn from_script(
+        ctx: &Context,
+        difficulty: Difficulty,
+        script_text: std::io::Result<String>,
+        mut assets: AssetCache,
+        script_path: String,
+    ) -> Self {
+        let (init, bindings, error) = match script_text {
+            Ok(ref text) => match parse_script(text) {
+                Ok((init, bindings)) => {
+                    report_script_warnings(&script_path, text, &init);
+                    (init, bindings, None)
+                }
+                Err(e) => (
+                    Globals::default(),
+                    KeyBindings::default(),
+                    Some(describe_script_error(&script_path, text, e)),
+                ),
+            },
+            Err(ref e) => (
+                Globals::default(),
+                KeyBindings::default(),
+                Some(format!("No {script_path} found: {e}")),
+            ),
+        };
+        let script_text = script_text.unwrap_or_default();
+
+        let seed = pick_seed(init.seed);
+
+        let b_spr = Sprite {
+            image: Some(assets.get_or_load(ctx, BULLET_IMG_PATH)),
+            color: Color::WHITE,
+            scale: BULLET_SCALE,
+        };
+        let tinted = |color: Color| Sprite {
+            color,
+            ..b_spr.clone()
+        };
+
+        let p_spr = Sprite {
+            image: Some(assets.get_or_load(ctx, PLAYER_IMG_PATH)),
+            color: Color::WHITE,
+            scale: 1.0,
+        };
+        let sized = |scale: f32, init: &InitData| {
+            Sprite {
+                scale,
+                ..p_spr.clone()
+            }
+            .scaled(init)
+        };
+
+        let (width, height) = (SCREEN_WIDTH, SCREEN_HEIGHT);
+        let screen = Screen { width, height };
+
+        let mut player = Player {
+            bombs: init.player.bombs(),
+            ..Player::new(
+                &sized(PLAYER_SCALE, &init.player.data),
+                init.player.health(),
+                init.player.speed(),
+                init.player.lives(),
+                Bullet::from_init(
+                    &Sprite::from_init(
+                        ctx,
+                        &mut assets,
+                        &init.player.bullet,
+                        tinted(Palette::Default.player_bullet()),
+                    ),
+                    DIR_UP,
+                    &init.player.bullet,
+                ),
+                init.player.bullet.amount,
+                init.player.spawn(PLAYER_SPAWN, &screen),
+            )
+        };
+        player.body.animation = AnimatedSprite::from_init(ctx, &mut assets, &init.player.data);
+        player.health.set_regen(init.player.data.regen);
+        player.hitbox = init.player.hitbox(PLAYER_HITBOX);
+        player.spell.origin_offset = init.player.bullet.origin_offset();
+        player.explosion = init.player.explosion();
+
+        // Set from the player's on_hit callback and consumed on the next combat update
+        let player_hit = Rc::new(Cell::new(false));
+        let player_near_death = Rc::new(Cell::new(false));
+        let (hit, near_death) = (player_hit.clone(), player_near_death.clone());
+        let max_health = player.health.max_health;
+        player.health.add_on_hit(move |hp| {
+            hit.set(true);
+            near_death.set(hp == 1 && max_health > 1);
+        });
+
+        let e_spr = Sprite {
+            image: Some(assets.get_or_load(ctx, ENEMY_IMG_PATH)),
+            color: Color::BLACK,
+            scale: 1.0,
+        };
+
+        let mut spawn_wave = |objects: &[InitObject], first_id: usize| -> Vec<Enemy> {
+            // Enemies without a spawn of their own line up in a row around the default
+            let spacing = ENEMY_SPACING * (objects.len() as f32 - 1.0) * 0.5;
+            let row = |i: usize| {
+                let [x, y] = ENEMY_SPAWN;
+                [x + (ENEMY_SPACING * i as f32 - spacing) / width, y]
+            };
+            objects
+                .iter()
+                .enumerate()
+                .map(|(i, enemy)| {
+                    let health = (enemy.health() as f32 * difficulty.health()).round() as u32;
+                    let phases = enemy.phases(difficulty);
+
+                    let animation = AnimatedSprite::from_init(ctx, &mut assets, &enemy.data);
+                    let bullet_sprite = Sprite::from_init(
+                        ctx,
+                        &mut assets,
+                        &enemy.bullet,
+                        tinted(Palette::Default.enemy_bullet()),
+                    );
+                    let (directions, mut move_delay) = (enemy.directions(), enemy.data.move_delay);
+                    if move_delay <= 0.0 && !enemy.data.patrol.is_empty() {
+                        move_delay = PATROL_STEP_DELAY;
+                    }
+                    let (resist_normal, resist_piercing) =
+                        (enemy.data.resist_normal, enemy.data.resist_piercing);
+                    let weakness = DamageType::parse(&enemy.data.weakness);
+                    let (telegraph, origin_offset) =
+                        (enemy.bullet.telegraph, enemy.bullet.origin_offset());
+                    let mut enemy = Enemy {
+                        id: first_id + i,
+                        phases,
+                        hitbox: enemy.hitbox(ENEMY_HITBOX),
+                        explosion: enemy.explosion(),
+                        boss: (!enemy.data.boss.is_empty()).then(|| enemy.data.boss.clone()),
+                        ..Enemy::new(
+                            &sized(ENEMY_SCALE, &enemy.data),
+                            enemy.spawn(row(i), &screen),
+                            health,
+                            enemy.speed(),
+                            Bullet::from_init(&bullet_sprite, DIR_DOWN, &enemy.bullet),
+                            enemy.bullet.amount,
+                            SpellPattern::from_init(&enemy.bullet),
+                        )
+                    };
+
+                    enemy.spell.scale_speed(difficulty.bullet_speed());
+                    enemy.spell.shot_timer.delay *= difficulty.shot_delay();
+                    enemy.body.animation = animation;
+                    enemy.spell.telegraph = telegraph;
+                    enemy.spell.origin_offset = origin_offset;
+                    if let Some(directions) = directions {
+                        enemy.directions = directions;
+                    }
+                    if move_delay.is_finite() && move_delay > 0.0 {
+                        enemy.move_timer = Timer::new(move_delay);
+                    }
+                    enemy
+                        .health
+                        .set_resistance(DamageType::Normal, resist_normal);
+                    enemy
+                        .health
+                        .set_resistance(DamageType::Piercing, resist_piercing);
+                    enemy.health.weakness = weakness;
+                    enemy
+                })
+                .collect()
+        };
+
+        let enemies = spawn_wave(&init.enemies, 0);
+        let mut first_id = init.enemies.len();
+        let waves = init
+            .waves
+            .iter()
+            .map(|wave| {
+                let enemies = spawn_wave(&wave.enemies, first_id);
+                first_id += wave.enemies.len();
+                Wave {
+                    enemies,
+                    spawn_delay: wave.delay,
+                }
+            })
+            .collect();
+
+        let (window_width, window_height) = ctx.gfx.drawable_size();
+        let window = Screen {
+            width: window_width,
+            height: window_height,
+        };
+
+        let background = load_background(ctx, &mut assets, &init.background);
+        let music = load_music(ctx, &init.music);
+        let continues = init.player.continues();
+
+        let story = if let Some(ref msg) = error {
+            story! {
+                p_spr: msg, [0., 0.], Color::RED,
+            }
+        } else {
+            story! {
+                p_spr: "The story begins...", [0., 0.],
+                e_spr as "Reimu": "I'm going to kill you!", [-width * 0.7, 0.]
+                    .choice("Fight", "fight")
+                    .choice("Talk", "talk"),
+                p_spr as "Sakuya": "Then come and try.", [0., 0.].labelled("fight").then("end"),
+                p_spr as "Sakuya": "Can't we talk this out?", [0., 0.].labelled("talk"),
+                e_spr as "Reimu": "Talk is cheap, danmaku isn't!", [-width * 0.7, 0.],
+            }
+        };
+
+        Self {
+            gamestate: GameState::Title,
+            watcher: ScriptWatcher::new(&script_path),
+            script_path,
+
+            screen,
+            window,
+            story,
+            fast_forward_timer: Timer::new(FAST_FORWARD_LINE_DELAY),
+
+            uis: VecDeque::from([title_menu()]),
+            sounds: SoundBank::new(ctx),
+            assets,
+            bindings,
+            player: Some(player),
+            enemies,
+            waves,
+            wave_timer: None,
+            background,
+            bg_offset: 0.0,
+            clear_color: init.clear_color(),
+            music,
+            script: error.is_none().then_some(init),
+
+            particles: vec![],
+            popups: vec![],
+            particle_sprite: tinted(Palette::Default.particle()),
+            power_ups: vec![],
+            shake: None,
+            player_hit,
+            player_near_death,
+
+            pauses_remaining: MAX_PAUSES,
+            continues,
+            debug_overlay: false,
+            show_hitboxes: false,
+            radar: true,
+            fullscreen: false,
+            graze: 0,
+            score: 0,
+            combo: Combo::new(),
+            practice_phase: 0,
+            difficulty,
+            palette: Palette::Default,
+            pause_blur: true,
+            auto_fire: false,
+            assist: false,
+            blur_shader: ShaderBuilder::new()
+                .fragment_code(BLUR_SHADER)
+                .build(ctx)
+                .map_err(|e| println!("Pause blur unavailable, dimming instead: {e}"))
+                .ok(),
+            scene: ScreenImage::new(ctx, None, 1.0, 1.0, 1),
+
+            script_text,
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            bomb_queued: false,
+            resume_queued: false,
+            step_time: 0.0,
+            time_scale: 1.0,
+            recording: vec![],
+            recording_stopped: false,
+            playback: None,
+
+            high_scores: HighScores::load(ctx),
+            name_entry: None,
+            name_key_fresh: false,
+            confirming_quit: false,
+            quit_interrupted: None,
+            quit_confirmed: false,
+            rebinding: None,
+        }
+    }
+
+    fn viewport(&self) -> Rect {
+        self.screen.viewport(&self.window)
+    }
+
+    fn to_logical(&self, x: f32, y: f32) -> Point2<f32> {
+        self.screen.to_logical(&self.window, x, y)
+    }
+
+    fn blurs_pause(&self) -> bool {
+        self.pause_blur && self.blur_shader.is_some() && self.gamestate == GameState::Paused
+    }
+
+    fn draw_layers(&self, ctx: &Context, canvas: &mut Canvas, layers: &[Layer]) {
+        let viewport = self.viewport();
+
+        // Kept in the old drawing order, so things sharing a layer still stack the same way
+        let entities: Vec<&dyn Renderable> = self
+            .enemies
+            .iter()
+            .map(|enemy| enemy as &dyn Renderable)
+            .chain(self.player.iter().map(|player| player as &dyn Renderable))
+            .chain(
+                self.enemies
+                    .iter()
+                    .map(|enemy| &enemy.spell as &dyn Renderable),
+            )
+            .chain(
+                self.player
+                    .iter()
+                    .map(|player| &player.spell as &dyn Renderable),
+            )
+            .chain(
+                self.particles
+                    .iter()
+                    .map(|particle| particle as &dyn Renderable),
+            )
+            .chain(
+                self.power_ups
+                    .iter()
+                    .map(|power_up| power_up as &dyn Renderable),
+            )
+            .chain(self.popups.iter().map(|popup| popup as &dyn Renderable))
+            .collect();
+
+        for &layer in layers {
+            // Only the playfield shakes, the HUD and menus stay put
+            match self.shake {
+                Some(ref shake) if layer.shakes() => {
+                    let Point2 { x, y } = shake.offset;
+                    canvas.set_screen_coordinates(Rect {
+                        x: viewport.x - x,
+                        y: viewport.y - y,
+                        ..viewport
+                    });
+                }
+                _ => canvas.set_screen_coordinates(viewport),
+            }
+
+            match layer {
+                Layer::Background => self.draw_background(canvas),
+                Layer::PlayerMarkers => self.draw_player_markers(ctx, canvas),
+                Layer::Hitboxes if self.show_hitboxes => self.draw_hitboxes(ctx, canvas),
+                Layer::Hud => self.draw_hud(ctx, canvas),
+                Layer::Overlay => self.draw_overlay(ctx, canvas, viewport),
+                _ => entities
+                    .iter()
+                    .filter(|entity| entity.layer() == layer)
+                    .for_each(|entity| entity.render(ctx, canvas, self.palette)),
+            }
+        }
+    }
+
+    fn draw_player_markers(&self, ctx: &Context, canvas: &mut Canvas) {
+        let Some(ref player) = self.player else {
+            return;
+        };
+
+        if player.is_focused {
+            let hitbox = circle!(ctx, 4.0, (255, 255, 255, 255));
+            let Point2 { x, y } = player.body.position();
+            draw_at!(canvas, &hitbox, (x, y));
+        }
+
+        // Faint bar under the player filling up until the next shot
+        let Point2 { x, y } = player.body.position();
+        let (x, y) = (x - COOLDOWN_BAR_WIDTH * 0.5, y + COOLDOWN_BAR_OFFSET);
+        let progress = player.spell.shot_timer.progress();
+
+        let track = rect!(ctx, COOLDOWN_BAR_WIDTH, 3.0, (255, 255, 255, 31));
+        draw_at!(canvas, &track, (x, y));
+
+        let mut color = self.palette.player_bullet();
+        color.a = 95.0 / 255.0;
+        let fill = rect!(ctx, COOLDOWN_BAR_WIDTH * progress, 3.0, color);
+        draw_at!(canvas, &fill, (x, y));
+    }
+
+    // Bullets collide as points against each target's radius, so bullets get a dot and
+    // everything they can hit gets its full circle
+    fn draw_hitboxes(&self, ctx: &Context, canvas: &mut Canvas) {
+        let faded = |color: Color| Color {
+            a: HITBOX_ALPHA,
+            ..color
+        };
+        let mut ring = |position: Point2<f32>, radius: f32, color: Color| {
+            let circle = circle!(ctx, radius, faded(color));
+            draw_at!(canvas, &circle, (position.x, position.y));
+        };
+
+        for enemy in self.enemies.iter() {
+            ring(enemy.body.position(), enemy.hitbox, Color::RED);
+            enemy.spell.for_each_visible(|bullet| {
+                ring(
+                    bullet.body.position(),
+                    HITBOX_BULLET_RADIUS,
+                    self.palette.enemy_bullet(),
+                )
+            });
+        }
+
+        if let Some(ref player) = self.player {
+            ring(player.body.position(), player.hitbox, Color::GREEN);
+            player.spell.for_each_visible(|bullet| {
+                ring(
+                    bullet.body.position(),
+                    HITBOX_BULLET_RADIUS,
+                    self.palette.player_bullet(),
+                )
+            });
+        }
+    }
+
+    fn draw_background(&self, canvas: &mut Canvas) {
+        let width = self.screen.width;
+        let height = self.screen.height;
+
+        let (w, h) = (
+            self.background.width() as f32,
+            self.background.height() as f32,
+        );
+
+        // Scrolling draws a second copy right above so the image wraps around seamlessly
+        let scale = [width / w, height / h];
+        canvas.draw(
+            &self.background,
+            DrawParam::default()
+                .dest([0.0, self.bg_offset])
+                .scale(scale),
+        );
+
+        if self.bg_offset > 0.0 {
+            canvas.draw(
+                &self.background,
+                DrawParam::default()
+                    .dest([0.0, self.bg_offset - height])
+                    .scale(scale),
+            );
+        }
+    }
+
+    fn draw_hud(&self, ctx: &Context, canvas: &mut Canvas) {
+        let height = self.screen.height;
+
+        // A single bar fits up there, so with several bosses around only the first one shows
+        if let Some((enemy, name)) = self
+            .enemies
+            .iter()
+            .find_map(|enemy| enemy.boss.as_ref().map(|name| (enemy, name)))
+        {
+            let width = self.screen.width - BOSS_HEALTHBAR_MARGIN * 2.0;
+            let healthbar = rect!(
+                ctx,
+                enemy.health.percentage() * width,
+                BOSS_HEALTHBAR_HEIGHT,
+                self.palette.enemy_health()
+            );
+            draw_at!(
+                canvas,
+                &healthbar,
+                (BOSS_HEALTHBAR_MARGIN, BOSS_HEALTHBAR_MARGIN + 25.0)
+            );
+
+            let name = Text::new(name.as_str());
+            draw_at!(
+                canvas,
+                &name,
+                (BOSS_HEALTHBAR_MARGIN, BOSS_HEALTHBAR_MARGIN)
+            );
+        }
+
+        if let Some(ref player) = self.player {
+            let healthbar = rect!(
+                ctx,
+                player.health.percentage() * 200.,
+                10.0,
+                self.palette.player_health()
+            );
+            draw_at!(canvas, &healthbar, (20.0, height - 30.0));
+
+            let lives = Text::new(format!("Lives: {}", player.lives));
+            draw_at!(canvas, &lives, (20.0, height - 55.0));
+
+            let bombs = Text::new(format!("Bombs: {}", player.bombs));
+            draw_at!(canvas, &bombs, (20.0, height - 105.0));
+        }
+
+        let graze = Text::new(format!("Graze: {}", self.graze));
+        draw_at!(canvas, &graze, (20.0, height - 80.0));
+
+        let score = Text::new(format!("Score: {}", self.score));
+        draw_at!(canvas, &score, (20.0, height - 130.0));
+
+        if self.assist {
+            let assist = Text::new("ASSIST");
+            draw_at!(canvas, &assist, (20.0, height - 155.0), ASSIST_LABEL_COLOR);
+        }
+
+        // The bar next to the multiplier shrinks until the combo drops a step
+        if self.combo.multiplier > 1 {
+            let combo = Text::new(format!("x{}", self.combo.multiplier));
+            draw_at!(canvas, &combo, (160.0, height - 130.0));
+
+            let left = 1.0 - self.combo.timer.progress();
+            let bar = rect!(ctx, COMBO_BAR_WIDTH * left, 4.0, (255, 255, 0, 191));
+            draw_at!(canvas, &bar, (200.0, height - 124.0));
+        }
+
+        if self.radar && !matches!(self.gamestate, GameState::Cinematic | GameState::Title) {
+            self.draw_radar(ctx, canvas);
+        }
+    }
+
+    // Enemies show as dots around the player, the ones out of range as arrows on the rim
+    fn draw_radar(&self, ctx: &Context, canvas: &mut Canvas) {
+        let (width, height) = (self.screen.width, self.screen.height);
+        let center = Point2 {
+            x: width - RADAR_MARGIN - RADAR_RADIUS,
+            y: height - RADAR_MARGIN - RADAR_RADIUS,
+        };
+        let origin = self.player.as_ref().map_or(
+            Point2 {
+                x: width * 0.5,
+                y: height * 0.5,
+            },
+            |player| player.body.position(),
+        );
+
+        let backdrop = circle!(ctx, RADAR_RADIUS, (255, 255, 255, 31));
+        draw_at!(canvas, &backdrop, (center.x, center.y));
+
+        let you = circle!(ctx, 3.0, (255, 255, 255, 255));
+        draw_at!(canvas, &you, (center.x, center.y));
+
+        let color = self.palette.enemy_bullet();
+        for enemy in self.enemies.iter() {
+            let offset = enemy
+                .body
+                .position()
+                .sub(origin)
+                .scale(RADAR_RADIUS / RADAR_RANGE);
+
+            if offset.length() <= RADAR_RADIUS {
+                let dot = circle!(ctx, 3.0, color);
+                draw_at!(canvas, &dot, (center.x + offset.x, center.y + offset.y));
+                continue;
+            }
+
+            let dir = offset.normalize();
+            let side = Point2 {
+                x: -dir.y,
+                y: dir.x,
+            }
+            .scale(RADAR_ARROW_SIZE * 0.5);
+            let tip = center.add(dir.scale(RADAR_RADIUS));
+            let base = center.add(dir.scale(RADAR_RADIUS - RADAR_ARROW_SIZE));
+
+            let arrow = Mesh::new_polygon(
+                ctx,
+                DrawMode::fill(),
+                &[tip, base.add(side), base.sub(side)],
+                color,
+            )
+            .unwrap();
+            canvas.draw(&arrow, DrawParam::default());
+        }
+    }
+
+    fn draw_overlay(&self, ctx: &Context, canvas: &mut Canvas, viewport: Rect) {
+        let width = self.screen.width;
+        let height = self.screen.height;
+
+        let half_width = width * 0.5;
+        let half_height = height * 0.5;
+
+        if self.run_over() {
+            let result = if self.gamestate == GameState::Victory {
+                "You win!"
+            } else {
+                "Game Over"
+            };
+            draw_at!(
+                canvas,
+                &centered_text(result),
+                (half_width, half_height - 100.0)
+            );
+
+            let score = centered_text(&format!("Final score: {}", self.score));
+            draw_at!(canvas, &score, (half_width, half_height - 40.0));
+        }
+
+        if let Some(line) = self
+            .story
+            .current()
+            .filter(|_| self.gamestate != GameState::Title)
+        {
+            let dialogue_box = rect!(ctx, width, height / 3.0, DIALOGUE_BOX_COLOR);
+            draw_at!(canvas, &dialogue_box, (0.0, height * 2.0 / 3.0));
+
+            if let Some(ref image) = line.sprite.image {
+                draw_at!(
+                    canvas,
+                    image,
+                    (width * 0.5 + line.pos.x, height * 0.5 + line.pos.y),
+                    line.sprite.color
+                );
+            }
+            draw_at!(
+                canvas,
+                &line.text,
+                (half_width, height * 5.0 / 6.0),
+                line.color
+            );
+
+            if let Some(ref speaker) = line.speaker {
+                let label = Text::new(TextFragment {
+                    text: speaker.clone(),
+                    scale: Some(PxScale::from(SPEAKER_LABEL_SIZE)),
+                    ..Default::default()
+                });
+                draw_at!(
+                    canvas,
+                    &label,
+                    (20.0, height * 2.0 / 3.0 + 10.0),
+                    SPEAKER_LABEL_COLOR
+                );
+            }
+        }
+
+        if self.gamestate == GameState::Title
+            || self.run_over()
+            || (self.gamestate == GameState::Paused && !self.blurs_pause())
+        {
+            let background = rect!(ctx, width, height, (0, 0, 0, 127));
+            draw_at!(canvas, &background, (0.0, 0.0));
+        }
+
+        let continuing = self.player.as_ref().is_some_and(|player| player.lives == 0);
+        if self.gamestate == GameState::Paused && !continuing {
+            let pauses = centered_text(&format!("Pauses left: {}", self.pauses_remaining));
+            draw_at!(canvas, &pauses, (half_width, half_height - 200.0));
+        }
+
+        if self.gamestate == GameState::Title {
+            draw_at!(
+                canvas,
+                &centered_text(GAME_TITLE),
+                (half_width, height * 0.2)
+            );
+        }
+
+        if self.gamestate == GameState::Title || self.run_over() {
+            draw_at!(
+                canvas,
+                &self.high_scores.table(),
+                (width - HIGH_SCORES_WIDTH, 20.0)
+            );
+        }
+
+        if let Some(ref name) = self.name_entry {
+            let prompt = centered_text(&format!(
+                "New high score! Name: {name:_<width$}  (Esc to skip)",
+                width = HIGH_SCORE_NAME_LENGTH
+            ));
+            draw_at!(canvas, &prompt, (half_width, half_height + 60.0));
+        }
+
+        // Only the menu on top of the stack is interactive, so it's the only one shown
+        self.uis.front().into_iter().for_each(|ui| {
+            if let Some(elem) = ui.front() {
+                let Point2 { x, y } = elem.pos;
+                draw_at!(
+                    canvas,
+                    &elem.img,
+                    (half_width + x, half_height + y),
+                    elem.select_color
+                );
+            }
+
+            ui.iter().skip(1).for_each(|elem| {
+                let Point2 { x, y } = elem.pos;
+                draw_at!(
+                    canvas,
+                    &elem.img,
+                    (half_width + x, half_height + y),
+                    elem.color
+                );
+            });
+
+            // Sliders show their value as a bar filling up beneath the label
+            ui.iter().for_each(|elem| {
+                if let UIAction::Slider { value, .. } = elem.action {
+                    let Point2 { x, y } = elem.pos;
+                    let (x, y) = (half_width + x - SLIDER_WIDTH * 0.5, half_height + y + 30.0);
+
+                    let track = rect!(ctx, SLIDER_WIDTH, 10.0, (255, 255, 255, 63));
+                    draw_at!(canvas, &track, (x, y));
+
+                    let fill = rect!(ctx, SLIDER_WIDTH * value(self), 10.0, (255, 255, 0, 191));
+                    draw_at!(canvas, &fill, (x, y));
+                }
+            });
+        });
+
+        // Black bars cover whatever the window shows beyond the playfield
+        if viewport.x < 0.0 {
+            let bar = rect!(ctx, -viewport.x, viewport.h, Color::BLACK);
+            draw_at!(canvas, &bar, (viewport.x, viewport.y));
+            draw_at!(canvas, &bar, (width, viewport.y));
+        }
+
+        if viewport.y < 0.0 {
+            let bar = rect!(ctx, viewport.w, -viewport.y, Color::BLACK);
+            draw_at!(canvas, &bar, (viewport.x, viewport.y));
+            draw_at!(canvas, &bar, (viewport.x, height));
+        }
+
+        if self.debug_overlay {
+            let count = |spell: &Spell| spell.bullets.iter().filter(|x| x.is_visible).count();
+            let player_bullets = self
+                .player
+                .as_ref()
+                .map_or(0, |player| count(&player.spell));
+            let enemy_bullets: usize = self.enemies.iter().map(|enemy| count(&enemy.spell)).sum();
+
+            let stats = Text::new(format!(
+                "FPS: {:.0}\nPlayer bullets: {player_bullets}\nEnemy bullets: {enemy_bullets}\nParticles: {}",
+                ctx.time.fps(),
+                self.particles.len(),
+            ));
+            draw_at!(canvas, &stats, (10.0, 10.0));
+        }
+    }
+
+    // Length of a combat step in game time, shorter while bullet time is on
+    fn delta(&self) -> f32 {
+        FIXED_DT * self.time_scale
+    }
+
+    fn on_combat_update(&mut self, ctx: &mut Context) -> GameResult {
+        let dt = self.delta();
+
+        // Eases back to full speed in real time, so the slowdown always lasts as long
+        let recovery = (1.0 - BULLET_TIME_SCALE) * FIXED_DT / BULLET_TIME_RECOVERY;
+        self.time_scale = (self.time_scale + recovery).min(1.0);
+
+        if let Some(ref mut shake) = self.shake {
+            if shake.timer.ready(dt) {
+                self.shake = None;
+            } else {
+                shake.update(&mut self.rng);
+            }
+        }
+
+        let input = match self.playback {
+            Some(ref mut frames) => frames.pop_front().unwrap_or_default(),
+            None => {
+                let input = InputFrame::read(ctx, &self.bindings, self.bomb_queued);
+                InputFrame {
+                    shoot: input.shoot || self.auto_fire,
+                    resumed: self.resume_queued,
+                    assist: self.assist,
+                    ..input
+                }
+            }
+        };
+
+        if self
+            .playback
+            .as_ref()
+            .is_some_and(|frames| frames.is_empty())
+        {
+            println!("Replay finished, handing control back");
+            self.playback = None;
+        }
+
+        self.bomb_queued = false;
+        self.resume_queued = false;
+        if !self.recording_stopped {
+            self.recording.push(input);
+        }
+
+        // Played back at the same frame they were recorded at, so the replay doesn't drift
+        if input.resumed {
+            self.reset_timers();
+        }
+        self.set_assist(input.assist);
+
+        if input.bomb {
+            self.on_bomb();
+        }
+
+        let mut hits = vec![];
+
+        if let Some(ref mut player) = self.player {
+            player.handle_input(dt, &input);
+            player.health.update(dt);
+
+            let mut world = UpdateCtx {
+                ctx,
+                dt,
+                screen: &self.screen,
+                sounds: &mut self.sounds,
+                graze: &mut self.graze,
+                hits: &mut hits,
+            };
+            player.update(&mut world, &mut self.enemies);
+
+            self.combo.update(dt);
+            for &(position, damage) in hits.iter() {
+                self.score += HIT_SCORE * self.combo.multiplier;
+                self.combo.hit();
+                self.popups.push(DamagePopup::new(position, damage));
+            }
+
+            if !player.health.is_alive() {
+                let Point2 { x, y } = player.body.position();
+                let sprite = player
+                    .spell
+                    .bullets
+                    .first()
+                    .map_or(&self.particle_sprite, |bullet| &bullet.body.sprite);
+
+                let spin = self.rng.gen_range(0.0..TAU);
+                self.particles
+                    .extend(burst(sprite, [x, y], player.explosion, spin));
+
+                self.sounds.play(ctx, Sound::Death);
+                player.lives = player.lives.saturating_sub(1);
+
+                if player.lives > 0 {
+                    player.respawn();
+                } else if self.continues > 0 {
+                    self.gamestate = GameState::Paused;
+                    self.uis.push_front(continue_menu(self.continues));
+                } else {
+                    self.player = None;
+                    self.end_run(GameState::GameOver);
+                }
+            }
+        }
+
+        let had_enemies = !self.enemies.is_empty();
+        let mut world = UpdateCtx {
+            ctx,
+            dt,
+            screen: &self.screen,
+            sounds: &mut self.sounds,
+            graze: &mut self.graze,
+            hits: &mut hits,
+        };
+
+        self.enemies.retain_mut(|enemy| {
+            enemy.update(&mut world, &mut self.player);
+
+            if !enemy.health.is_alive() {
+                world.sounds.play(ctx, Sound::Death);
+                self.shake = Some(ScreenShake::new(SHAKE_DEATH_MAGNITUDE));
+
+                let Point2 { x, y } = enemy.body.position();
+                let sprite = enemy
+                    .spell
+                    .bullets
+                    .first()
+                    .map_or(&self.particle_sprite, |bullet| &bullet.body.sprite);
+
+                let spin = self.rng.gen_range(0.0..TAU);
+                self.particles
+                    .extend(burst(sprite, [x, y], enemy.explosion, spin));
+
+                self.power_ups
+                    .extend(PowerUp::roll(&mut self.rng, &self.particle_sprite, [x, y]));
+            }
+
+            enemy.health.is_alive()
+        });
+
+        self.particles.retain_mut(|particle| {
+            particle.update(&mut world, &mut ());
+            particle.bullet.is_visible
+        });
+
+        self.popups.retain_mut(|popup| {
+            popup.update(&mut world, &mut ());
+            !popup.is_done()
+        });
+
+        self.power_ups.retain_mut(|power_up| {
+            power_up.update(&mut world, &mut ());
+
+            if let Some(ref mut player) = self.player {
+                if power_up.collected_by(player) {
+                    power_up.kind.apply(player);
+                    return false;
+                }
+            }
+
+            power_up.body.position().y < self.screen.height
+        });
+
+        if let Some(ref mut player) = self.player {
+            for enemy in self.enemies.iter_mut() {
+                let cancelled = player.spell.cancel(&mut enemy.spell);
+                self.graze += cancelled;
+                self.score += cancelled * CANCEL_SCORE;
+            }
+        }
 
-fn centered_text(text: &str) -> Text {
-    Text::new(TextFragment {
-        text: text.to_owned(),
-        scale: Some(PxScale::from(40.0)),
-        ..Default::default()
-    })
-    .set_layout(TextLayout {
-        h_align: TextAlign::Middle,
-        v_align: TextAlign::Middle,
-    })
-    .to_owned()
-}
+        if self.player_hit.replace(false) {
+            self.shake = Some(ScreenShake::new(SHAKE_HIT_MAGNITUDE));
+            self.combo.reset();
 
-fn pause_menu() -> UIMenu {
-    [
-        UISelectable {
-            img: centered_text("Resume"),
-            pos: Point2 { x: 0., y: -100. },
-            color: Color::WHITE,
-            select_color: Color::YELLOW,
-            action: |_, state| {
-                state.uis.remove(0);
-                state.gamestate = if state.story.is_empty() {
-                    GameState::Combat
-                } else {
-                    GameState::Cinematic
-                };
-            },
-        },
-        UISelectable {
-            img: centered_text("Reset"),
-            pos: Point2 { x: 0., y: 0. },
-            color: Color::WHITE,
-            select_color: Color::YELLOW,
-            action: |ctx, state| state.restart(ctx),
-        },
-        UISelectable {
-            img: centered_text("Quit"),
-            pos: Point2 { x: 0., y: 100. },
-            color: Color::WHITE,
-            select_color: Color::RED,
-            action: |ctx, _| ctx.request_quit(),
-        },
-    ]
-    .into()
-}
+            // One hit away from losing a life, slow everything down to give a chance to escape.
+            // Read from the hit itself, since a death has already refilled health by now
+            if self.player_near_death.replace(false) {
+                self.time_scale = BULLET_TIME_SCALE;
+            }
+        }
 
-fn get_script_mod_date() -> std::time::SystemTime {
-    let metadata = std::fs::metadata("script.th").unwrap();
-    metadata.modified().unwrap()
-}
+        if self.enemies.is_empty() {
+            if let Some(wave) = self.waves.front() {
+                let timer = self
+                    .wave_timer
+                    .get_or_insert_with(|| Timer::new(wave.spawn_delay));
 
-impl State {
-    fn new(ctx: &Context) -> Self {
-        let script_text = std::fs::read_to_string("script.th").unwrap();
-        let init_panic = std::panic::catch_unwind(|| Globals::from_str(&script_text));
+                if timer.ready(dt) {
+                    self.wave_timer = None;
+                    self.enemies = self.waves.pop_front().unwrap().enemies;
+                }
+            } else if had_enemies {
+                self.end_run(GameState::Victory);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn run_over(&self) -> bool {
+        matches!(self.gamestate, GameState::GameOver | GameState::Victory)
+    }
+
+    // Holding fast-forward speeds up the typing and moves past finished lines on its own,
+    // stopping at choices since those still need picking
+    fn on_cinematic_update(&mut self, ctx: &Context) {
+        let held = ctx.keyboard.is_key_pressed(self.bindings.fast_forward);
+        let Some(line) = self.story.current_mut() else {
+            return;
+        };
 
-        let init = if let Ok(ref init) = init_panic {
-            init
+        line.update(if held {
+            FIXED_DT * FAST_FORWARD_SCALE
         } else {
-            &Globals::default()
+            FIXED_DT
+        });
+
+        if !held || !line.is_revealed() || !line.choices.is_empty() {
+            self.fast_forward_timer.reset();
+            return;
+        }
+
+        if self.fast_forward_timer.ready(FIXED_DT) {
+            self.story.advance();
+            if self.story.is_empty() {
+                self.gamestate = GameState::Combat;
+            }
+        }
+    }
+
+    // Good enough runs ask for a name before the table gets updated
+    fn end_run(&mut self, result: GameState) {
+        self.gamestate = result;
+        self.uis.clear();
+        self.uis.push_front(run_over_menu());
+
+        // Practice runs can't be lost, so they don't count towards the table
+        let practice = self
+            .player
+            .as_ref()
+            .is_some_and(|player| player.health.invincible);
+
+        if !practice && self.high_scores.qualifies(self.score) {
+            self.name_entry = Some(String::new());
+        }
+    }
+
+    fn on_name_input(&mut self, character: char) {
+        let Some(ref mut name) = self.name_entry else {
+            return;
         };
 
-        let b_spr = Sprite {
-            image: load_image(ctx, BULLET_IMG_PATH),
-            color: Color::WHITE,
+        if character.is_ascii_alphabetic() && name.len() < HIGH_SCORE_NAME_LENGTH {
+            name.push(character.to_ascii_uppercase());
+        }
+    }
+
+    fn on_name_key(&mut self, ctx: &Context, key: KeyCode) {
+        let Some(ref mut name) = self.name_entry else {
+            return;
         };
 
-        let p_spr = Sprite {
-            image: load_image(ctx, PLAYER_IMG_PATH),
-            color: Color::WHITE,
+        match key {
+            KeyCode::Back => {
+                name.pop();
+            }
+            KeyCode::Escape => self.name_entry = None,
+            KeyCode::Return if name.len() == HIGH_SCORE_NAME_LENGTH => {
+                let name = self.name_entry.take().unwrap();
+                self.high_scores.insert(name, self.score);
+
+                if let Err(e) = self.high_scores.save(ctx) {
+                    println!("Couldn't save the high scores: {e}");
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn on_confirm(&mut self, ctx: &mut Context) {
+        match self.gamestate {
+            GameState::Cinematic => {
+                let chosen = self
+                    .uis
+                    .front()
+                    .and_then(|ui| ui.front())
+                    .map(|elem| elem.action);
+
+                match (chosen, self.story.current_mut()) {
+                    (Some(UIAction::Choice(index)), Some(line)) => {
+                        let label = line.choices[index].1.clone();
+                        self.uis.clear();
+                        self.story.jump(&label);
+                    }
+                    (_, Some(line)) if !line.is_revealed() => line.reveal(),
+                    (_, Some(line)) if !line.choices.is_empty() => {
+                        let menu = choice_menu(&line.choices);
+                        self.uis.push_front(menu);
+                    }
+                    _ => self.story.advance(),
+                }
+
+                if self.story.is_empty() {
+                    self.gamestate = GameState::Combat;
+                }
+            }
+            GameState::Paused | GameState::Title | GameState::GameOver | GameState::Victory => {
+                if let Some(UIAction::Button(action)) = self.uis[0].front().map(|elem| elem.action)
+                {
+                    action(ctx, self);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Brings the player back with everything else left as it was when they fell
+    fn on_continue(&mut self) {
+        let Some(ref mut player) = self.player else {
+            return;
         };
 
-        let player = Player::new(
-            &p_spr,
-            init.player.health(),
-            Bullet::new(&b_spr, DIR_UP, init.player.bullet.speed),
-            init.player.bullet.amount,
-        );
+        self.continues -= 1;
+        player.lives = player.max_lives;
+        player.respawn();
 
-        let e_spr = Sprite {
-            image: load_image(ctx, ENEMY_IMG_PATH),
-            color: Color::BLACK,
+        self.resume_queued = true;
+        self.gamestate = GameState::Combat;
+    }
+
+    // The next key press goes to this binding instead of the game
+    fn start_rebind(&mut self, binding: Binding) {
+        self.rebinding = Some(binding);
+        if let Some(elem) = self.uis[0].front_mut() {
+            elem.img = centered_text("Press a key...");
+        }
+    }
+
+    // Escape backs out and keeps the old key
+    fn on_rebind_key(&mut self, binding: Binding, key: KeyCode) {
+        self.rebinding = None;
+        if key != KeyCode::Escape {
+            *self.bindings.key_mut(binding) = key;
+        }
+
+        if let Some(elem) = self.uis[0].front_mut() {
+            elem.img = centered_text(&self.bindings.label(binding));
+        }
+    }
+
+    fn on_skip(&mut self) {
+        if self.gamestate == GameState::Cinematic {
+            self.story.clear();
+            self.uis.clear();
+            self.gamestate = GameState::Combat;
+        }
+    }
+
+    // Closing from mid-run pauses first, so backing out lands on the pause menu
+    fn confirm_quit(&mut self) {
+        if self.confirming_quit {
+            return;
+        }
+
+        if matches!(self.gamestate, GameState::Combat | GameState::Cinematic) {
+            let gamestate = std::mem::replace(&mut self.gamestate, GameState::Paused);
+            self.quit_interrupted = Some(gamestate);
+        }
+
+        self.confirming_quit = true;
+        self.uis.push_front(quit_menu());
+    }
+
+    fn on_pause(&mut self) {
+        let can_pause = matches!(self.gamestate, GameState::Combat | GameState::Cinematic);
+
+        if can_pause && self.pauses_remaining > 0 {
+            self.pauses_remaining -= 1;
+            self.gamestate = GameState::Paused;
+            self.uis.push_front(pause_menu());
+        }
+    }
+
+    fn on_hover(&mut self, ctx: &Context, x: f32, y: f32) -> bool {
+        let Some(menu) = self.uis.front_mut() else {
+            return false;
         };
 
-        let enemy = Enemy::new(
-            &p_spr,
-            init.enemy.health(),
-            init.enemy.speed(),
-            Bullet::new(&b_spr, DIR_DOWN, init.enemy.bullet.speed),
-            init.enemy.bullet.amount,
-        );
+        let hovered = menu.iter().position(|elem| {
+            elem.bounds(ctx, &self.screen)
+                .is_some_and(|bounds| bounds.contains([x, y]))
+        });
 
-        let (width, height) = ctx.gfx.size();
-        let screen = Screen { width, height };
+        if let Some(index) = hovered {
+            menu.rotate_left(index);
+        }
 
-        let background = load_image(ctx, format!("/{}/", init.background).as_str());
+        hovered.is_some()
+    }
 
-        let story = if let Err(e) = init_panic {
-            let msg = e.downcast_ref::<String>().unwrap();
-            story! {
-                p_spr: msg, [0., 0.], Color::BLACK,
+    fn on_adjust(&mut self, forward: bool) {
+        match self.uis[0].front().map(|elem| elem.action) {
+            Some(UIAction::Slider { value, on_change }) => {
+                let step = if forward { SLIDER_STEP } else { -SLIDER_STEP };
+                on_change(self, (value(self) + step).clamp(0.0, 1.0));
             }
+            _ => self.on_navigate(forward),
+        }
+    }
+
+    fn on_navigate(&mut self, forward: bool) {
+        if forward {
+            self.uis[0].rotate_left(1);
         } else {
-            story! {
-                p_spr: "The story begins...", [0., 0.],
-                e_spr: "I'm going to kill you!", [-width * 0.7, 0.],
+            self.uis[0].rotate_right(1);
+        }
+    }
+
+    fn on_bomb(&mut self) {
+        let Some(ref mut player) = self.player else {
+            return;
+        };
+
+        if self.gamestate != GameState::Combat || player.bombs == 0 {
+            return;
+        }
+
+        player.bombs -= 1;
+        player.health.make_invulnerable(BOMB_INVULNERABILITY);
+
+        for enemy in self.enemies.iter_mut() {
+            enemy
+                .spell
+                .bullets
+                .iter_mut()
+                .for_each(|x| x.is_visible = false);
+            let damage = enemy.health.take_damage(BOMB_DAMAGE, DamageType::Normal);
+            if let Some(damage) = damage.filter(|&damage| damage > 0) {
+                self.popups
+                    .push(DamagePopup::new(enemy.body.position(), damage));
+            }
+        }
+
+        let Point2 { x, y } = player.body.position();
+        self.particles.extend(burst(
+            &self.particle_sprite,
+            [x, y],
+            (BOMB_SHOCKWAVE_PARTICLES, 1.0, 400.0),
+            0.0,
+        ));
+    }
+
+    // The new window size comes back through resize_event
+    fn toggle_fullscreen(&mut self, ctx: &mut Context) -> GameResult {
+        self.fullscreen = !self.fullscreen;
+        ctx.gfx.set_fullscreen(if self.fullscreen {
+            conf::FullscreenType::Desktop
+        } else {
+            conf::FullscreenType::Windowed
+        })
+    }
+
+    fn shatter_screen(&mut self) {
+        let center = [self.screen.width * 0.5, self.screen.height * 0.5];
+
+        self.particles.extend(burst(
+            &self.particle_sprite,
+            center,
+            (SHATTER_SHARDS, 0.5, 600.0),
+            0.0,
+        ));
+    }
+
+    fn reset_timers(&mut self) {
+        if let Some(ref mut player) = self.player {
+            player.spell.shot_timer.reset();
+        }
+
+        for enemy in self.enemies.iter_mut() {
+            enemy.spell.shot_timer.reset();
+            enemy.move_timer.reset();
+        }
+    }
+
+    fn update_music(&mut self, ctx: &Context) {
+        if let Some(ref mut music) = self.music {
+            music.set_volume(self.sounds.volume);
+
+            match self.gamestate {
+                GameState::Combat if music.stopped() => {
+                    music.play(ctx).ok();
+                }
+                GameState::Combat if music.paused() => music.resume(),
+                GameState::Paused if music.playing() => music.pause(),
+                _ => {}
+            }
+        }
+    }
+
+    fn reload(&mut self, ctx: &mut Context) {
+        // The file can be briefly missing mid save, the next event picks it back up
+        let Ok(script_text) = read_script(&self.script_path) else {
+            return;
+        };
+
+        match parse_script(&script_text) {
+            Ok((init, bindings))
+                if self
+                    .script
+                    .as_ref()
+                    .is_some_and(|script| !is_structural_change(script, &init)) =>
+            {
+                println!("Script Reloaded!");
+                report_script_warnings(&self.script_path, &script_text, &init);
+                self.apply_script(ctx, init, bindings);
+
+                // The replay keeps the script its frames were played with
+                if self.recording.is_empty() {
+                    self.script_text = script_text;
+                } else if !self.recording_stopped {
+                    println!("Script changed mid-run, the replay stops here");
+                    self.recording_stopped = true;
+                }
+            }
+            _ => self.restart(ctx),
+        }
+    }
+
+    fn apply_script(&mut self, ctx: &mut Context, init: Globals, bindings: KeyBindings) {
+        if self.script_bindings() != Some(bindings) {
+            self.bindings = bindings;
+        }
+        let script = self.script.take().unwrap_or_default();
+
+        if init.background != script.background {
+            self.background = load_background(ctx, &mut self.assets, &init.background);
+        }
+        self.clear_color = init.clear_color();
+
+        if init.music != script.music {
+            if let Some(ref mut music) = self.music {
+                music.stop(ctx).ok();
+            }
+            self.music = load_music(ctx, &init.music);
+        }
+
+        if let Some(ref mut player) = self.player {
+            player.body.rigidbody.speed = init.player.speed();
+            player.spell.apply_init(&init.player.bullet);
+        }
+
+        let scripts: Vec<_> = init.all_enemies().collect();
+        let waiting = self
+            .waves
+            .iter_mut()
+            .flat_map(|wave| wave.enemies.iter_mut());
+
+        for enemy in self.enemies.iter_mut().chain(waiting) {
+            let script = scripts[enemy.id];
+            enemy.body.rigidbody.speed = script.speed();
+            enemy.spell.apply_init(&script.bullet);
+            enemy.spell.scale_speed(self.difficulty.bullet_speed());
+
+            // Enemies already past a phase go back to that phase's pattern, not the base one
+            let mut phases = script.phases(self.difficulty);
+            let entered = phases.len().saturating_sub(enemy.phases.len());
+            enemy.phases = phases.split_off(entered);
+            if let Some((_, pattern, delay)) = phases.pop() {
+                enemy.spell.pattern = pattern;
+                enemy.spell.shot_timer.delay = delay;
+            }
+            if self.assist {
+                enemy.spell.scale_speed(ASSIST_BULLET_SPEED);
             }
+        }
+
+        self.script = Some(init);
+    }
+
+    // In-game rebinds win until the script's own input block changes, on reloads and restarts alike
+    fn script_bindings(&self) -> Option<KeyBindings> {
+        self.script
+            .as_ref()
+            .map(|script| KeyBindings::from_init(&script.input))
+    }
+
+    // Covers enemies waiting in later waves too, so the whole run plays the same way
+    fn set_assist(&mut self, assist: bool) {
+        if self.assist == assist {
+            return;
+        }
+
+        self.assist = assist;
+        let factor = if assist {
+            ASSIST_BULLET_SPEED
+        } else {
+            1.0 / ASSIST_BULLET_SPEED
+        };
+
+        let waiting = self
+            .waves
+            .iter_mut()
+            .flat_map(|wave| wave.enemies.iter_mut());
+        for enemy in self.enemies.iter_mut().chain(waiting) {
+            enemy.spell.sparse = assist;
+            enemy.spell.scale_speed(factor);
+        }
+    }
+
+    fn restart(&mut self, ctx: &mut Context) {
+        println!("Game Restarted!");
+
+        let assets = std::mem::take(&mut self.assets);
+        let state = Self::new(ctx, self.difficulty, assets, self.script_path.clone());
+        self.replace(ctx, state);
+
+        // The title screen only greets a fresh launch
+        self.uis.clear();
+        self.gamestate = GameState::Cinematic;
+    }
+
+    // Swaps in a fresh run while keeping the player's settings
+    fn replace(&mut self, ctx: &mut Context, state: State) {
+        if let Some(ref mut music) = self.music {
+            music.stop(ctx).ok();
+        }
+
+        let volume = self.sounds.volume;
+        let debug_overlay = self.debug_overlay;
+        let show_hitboxes = self.show_hitboxes;
+        let radar = self.radar;
+        let fullscreen = self.fullscreen;
+        let bindings = (self.script_bindings() == state.script_bindings()).then_some(self.bindings);
+        let palette = self.palette;
+        let pause_blur = self.pause_blur;
+        let auto_fire = self.auto_fire;
+        let assist = self.assist;
+        *self = state;
+        self.sounds.volume = volume;
+        self.debug_overlay = debug_overlay;
+        self.show_hitboxes = show_hitboxes;
+        self.radar = radar;
+        self.fullscreen = fullscreen;
+        if let Some(bindings) = bindings {
+            self.bindings = bindings;
+        }
+        self.palette = palette;
+        self.pause_blur = pause_blur;
+        self.auto_fire = auto_fire;
+        self.set_assist(assist);
+    }
+
+    fn save_replay(&self) {
+        let replay = Replay {
+            difficulty: self.difficulty,
+            seed: self.seed,
+            assist: self.assist,
+            script: self.script_text.clone(),
+            frames: self.recording.clone(),
+        };
+
+        match replay.save(REPLAY_PATH) {
+            Ok(()) => println!("Replay saved to {REPLAY_PATH}"),
+            Err(e) => println!("Couldn't save the replay: {e}"),
+        }
+    }
+
+    // The boss is whichever enemy has the most phases scripted
+    fn practice_phases(&self) -> usize {
+        self.enemies
+            .iter()
+            .map(|enemy| enemy.phases.len())
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Drops straight into combat at the chosen phase, with a player that can't be hurt
+    fn start_practice(&mut self, ctx: &mut Context) {
+        let phase = self.practice_phase;
+        self.restart(ctx);
+        self.story.clear();
+        self.gamestate = GameState::Combat;
+
+        if let Some(boss) = self
+            .enemies
+            .iter_mut()
+            .max_by_key(|enemy| enemy.phases.len())
+        {
+            boss.set_phase(phase);
+        }
+
+        if let Some(ref mut player) = self.player {
+            player.health.invincible = true;
+        }
+    }
+
+    fn play_replay(&mut self, ctx: &mut Context) {
+        let Some(replay) = Replay::load(REPLAY_PATH) else {
+            println!("No replay found at {REPLAY_PATH}");
+            return;
         };
 
-        Self {
-            gamestate: GameState::Cinematic,
-            last_update: get_script_mod_date(),
+        let assets = std::mem::take(&mut self.assets);
+        let state = Self::from_script(
+            ctx,
+            replay.difficulty,
+            Ok(replay.script),
+            assets,
+            self.script_path.clone(),
+        );
+        self.replace(ctx, state);
+        self.set_assist(replay.assist);
+        self.seed = replay.seed;
+        self.rng = StdRng::seed_from_u64(replay.seed);
+
+        // Replays only cover combat, the story already played out when it was recorded
+        self.uis.clear();
+        self.story.clear();
+        self.gamestate = GameState::Combat;
+        self.playback = Some(replay.frames.into());
+    }
+}
+
+const GAME_TITLE: &str = "Touhou Engine";
+const SCREEN_WIDTH: f32 = 800.0;
+const SCREEN_HEIGHT: f32 = 600.0;
+const SCRIPT_PATH: &str = "script.th";
+const SCRIPT_RELOAD_DEBOUNCE: f32 = 0.2;
+const SCRIPT_FIELDS: &[&[&str]] = &[
+    Globals::FIELDS,
+    InitData::FIELDS,
+    InitKeys::FIELDS,
+    InitWave::FIELDS,
+    InitObject::FIELDS,
+];
+
+const PLAYER_IMG_PATH: &str = "/sakuya.png";
+const ENEMY_IMG_PATH: &str = "/sakuya.png";
+const BULLET_IMG_PATH: &str = "/isaac.png";
+
+const SHOOT_SFX_PATH: &str = "/shoot.ogg";
+const HIT_SFX_PATH: &str = "/hit.ogg";
+const DEATH_SFX_PATH: &str = "/death.ogg";
+
+const ENEMY_SPACING: f32 = 150.0;
+// Fractions of the screen, matching the old fixed spots on the 800x600 view
+const PLAYER_SPAWN: [f32; 2] = [350.0 / SCREEN_WIDTH, 350.0 / SCREEN_HEIGHT];
+const ENEMY_SPAWN: [f32; 2] = [350.0 / SCREEN_WIDTH, 100.0 / SCREEN_HEIGHT];
+
+const CLEAR_COLOR: Color = Color::new(
+    0x2b as f32 / 255.0,
+    0x2c as f32 / 255.0,
+    0x2f as f32 / 255.0,
+    1.0,
+);
+
+const NAMED_COLORS: &[(&str, Color)] = &[
+    ("white", Color::WHITE),
+    ("black", Color::BLACK),
+    ("red", Color::RED),
+    ("green", Color::GREEN),
+    ("blue", Color::BLUE),
+    ("cyan", Color::CYAN),
+    ("magenta", Color::MAGENTA),
+    ("yellow", Color::YELLOW),
+];
+
+const STORY_REVEAL_DELAY: f32 = 0.03;
+const FAST_FORWARD_SCALE: f32 = 4.0;
+const FAST_FORWARD_LINE_DELAY: f32 = 0.25;
+const DIALOGUE_BOX_COLOR: Color = Color::new(0.0, 0.0, 0.0, 0.6);
+const SPEAKER_LABEL_SIZE: f32 = 24.0;
+const SPEAKER_LABEL_COLOR: Color = Color::new(1.0, 0.85, 0.3, 1.0);
+
+const FIXED_DT: f32 = 1.0 / 120.0;
+const MAX_STEPS_PER_FRAME: u32 = 12;
+const MAX_FRAME_TIME: f32 = 0.1;
+
+const SLIDER_STEP: f32 = 0.1;
+const SLIDER_WIDTH: f32 = 200.0;
 
-            screen,
-            story,
+const MAX_PAUSES: u32 = 3;
+const SHATTER_SHARDS: usize = 24;
 
-            uis: VecDeque::new(),
-            player: Some(player),
-            enemy: Some(enemy),
-            background,
+const BLUR_SHADER: &str = include_str!("blur.wgsl");
 
-            particles: vec![],
-            texts: vec![],
-        }
-    }
+const GAMEPAD_DEADZONE: f32 = 0.2;
 
-    fn draw_body(&self, canvas: &mut Canvas, body: &Body, size: f32, color: Color) {
-        canvas.draw(
-            &body.sprite.image,
-            DrawParam::new()
-                .dest(body.position)
-                .scale([size, size])
-                .color(color)
-                .offset([0.5, 0.5]),
-        );
-    }
+const RESPAWN_INVULNERABILITY: f32 = 2.0;
+const PLAYER_IFRAMES: f32 = 1.0;
+const IFRAMES_FLASH_RATE: f32 = 10.0;
+const PLAYER_HITBOX: f32 = 25.0;
+const ENEMY_HITBOX: f32 = 100.0;
+const GRAZE_RADIUS: f32 = 60.0;
+const HITBOX_ALPHA: f32 = 0.35;
+const HITBOX_BULLET_RADIUS: f32 = 3.0;
+const KNOCKBACK_IMPULSE: f32 = 400.0;
+const KNOCKBACK_DURATION: f32 = 0.15;
 
-    fn on_combat_update(&mut self, ctx: &mut Context) -> GameResult {
-        for key in [KeyCode::W, KeyCode::S, KeyCode::A, KeyCode::D] {
-            if ctx.keyboard.is_key_pressed(key) {
-                let dir = match key {
-                    KeyCode::W => DIR_UP,
-                    KeyCode::S => DIR_DOWN,
-                    KeyCode::A => DIR_LEFT,
-                    KeyCode::D => DIR_RIGHT,
-                    _ => return Ok(()),
-                };
+const DASH_SPEED: f32 = 900.0;
+const DASH_DURATION: f32 = 0.15;
+const DASH_COOLDOWN: f32 = 1.0;
 
-                if let Some(Player { ref mut body, .. }) = self.player {
-                    body.position.x += dir[0] * body.speed;
-                    body.position.y += dir[1] * body.speed;
-                }
-            }
-        }
+const SHOT_BUFFER: f32 = 0.1;
 
-        if let Some(ref mut player) = self.player {
-            player.update(&ctx, &mut self.enemy);
+const MAX_TRAIL_LENGTH: usize = 8;
+const TRAIL_SPACING: f32 = 1.0 / 60.0;
+const TRAIL_ALPHA: f32 = 0.5;
 
-            if !player.health.is_alive() {
-                let Point2 { x, y } = player.body.position;
-                let sprite = &player.spell.bullets.first().unwrap().body.sprite;
+const DEATH_PARTICLES: usize = 4;
+const DEATH_PARTICLE_TTL: f32 = 2.0;
+const DEATH_PARTICLE_SPEED: f32 = 300.0;
 
-                for dir in [DIR_UP, DIR_DOWN, DIR_LEFT, DIR_RIGHT] {
-                    self.particles
-                        .push(Particle::new(sprite, 2.0, [x, y], dir, 5.0));
-                }
+const PATROL_STEPS: usize = 16;
+const PATROL_STEP_DELAY: f32 = 0.25;
 
-                self.texts
-                    .push(centered_text("You died! Press R to restart."));
+const COOLDOWN_BAR_WIDTH: f32 = 40.0;
+const COOLDOWN_BAR_OFFSET: f32 = 40.0;
 
-                self.player = None;
-            }
-        }
+const ENEMY_HEALTHBAR_WIDTH: f32 = 100.0;
+const ENEMY_HEALTHBAR_OFFSET: f32 = 90.0;
+const BOSS_HEALTHBAR_HEIGHT: f32 = 12.0;
+const BOSS_HEALTHBAR_MARGIN: f32 = 20.0;
 
-        if let Some(ref mut enemy) = self.enemy {
-            enemy.update(&ctx, &mut self.player, &self.screen);
+const PHASE_FLASH_DURATION: f32 = 0.3;
 
-            if !enemy.health.is_alive() {
-                let Point2 { x, y } = enemy.body.position;
-                let sprite = &enemy.spell.bullets.first().unwrap().body.sprite;
+const BULLET_TIME_SCALE: f32 = 0.3;
+const BULLET_TIME_RECOVERY: f32 = 1.0;
 
-                for dir in [DIR_UP, DIR_DOWN, DIR_LEFT, DIR_RIGHT] {
-                    self.particles
-                        .push(Particle::new(sprite, 2.0, [x, y], dir, 5.0));
-                }
+const SHAKE_DURATION: f32 = 0.3;
+const SHAKE_HIT_MAGNITUDE: f32 = 8.0;
+const SHAKE_DEATH_MAGNITUDE: f32 = 12.0;
 
-                self.texts
-                    .push(centered_text("You win! Press R to restart."));
+const PLAYER_SCALE: f32 = 0.12;
+const ENEMY_SCALE: f32 = 0.2;
+const BULLET_SCALE: f32 = 0.05;
+const POWER_UP_SCALE: f32 = 0.08;
 
-                self.enemy = None;
-            }
-        }
+const POWER_UP_SPEED: f32 = 100.0;
+const POWER_UP_DROP_CHANCE: f64 = 0.5;
+const POWER_UP_RADIUS: f32 = 30.0;
 
-        self.particles.retain_mut(|particle| {
-            particle.update(ctx);
-            particle.bullet.is_visible
-        });
+const TELEGRAPH_RADIUS: f32 = 80.0;
+const TELEGRAPH_MIN_RADIUS: f32 = 20.0;
+const TELEGRAPH_WIDTH: f32 = 3.0;
 
-        Ok(())
-    }
+const DAMAGE_POPUP_TTL: f32 = 0.6;
+const DAMAGE_POPUP_SPEED: f32 = 60.0;
+const DAMAGE_POPUP_OFFSET: f32 = 20.0;
+const POWER_UP_BULLETS: usize = 2;
+const MAX_PLAYER_BULLETS: usize = 40;
+const OFF_WEAKNESS_DAMAGE: f32 = 0.0;
+const WEAKNESS_NORMAL_TINT: Color = Color::new(0.9, 0.3, 0.3, 1.0);
+const WEAKNESS_PIERCING_TINT: Color = Color::new(0.3, 0.6, 0.9, 1.0);
 
-    fn restart(&mut self, ctx: &mut Context) {
-        println!("Game Restarted!");
-        *self = Self::new(ctx);
-    }
-}
+const MAX_SHOT_COUNT: usize = 4;
+// Degrees between the outermost shots of a volley, and the gap between neighbouring ones
+const PLAYER_SHOT_SPREAD: f32 = 12.0;
+const SHOT_SPACING: f32 = 12.0;
+const MAX_BOMBS: u32 = 8;
 
-const PLAYER_IMG_PATH: &str = "/sakuya.png";
-const ENEMY_IMG_PATH: &str = "/sakuya.png";
-const BULLET_IMG_PATH: &str = "/isaac.png";
+const REPLAY_PATH: &str = "replay.txt";
+
+const HIGH_SCORES_FILE: &str = "highscores.txt";
+const MAX_HIGH_SCORES: usize = 5;
+const HIGH_SCORE_NAME_LENGTH: usize = 3;
+const HIGH_SCORES_WIDTH: f32 = 180.0;
+
+const HIT_SCORE: u32 = 1;
+const COMBO_DURATION: f32 = 1.5;
+const COMBO_STEP: u32 = 1;
+const MAX_COMBO: u32 = 8;
+const COMBO_BAR_WIDTH: f32 = 60.0;
+
+const ASSIST_BULLET_SPEED: f32 = 0.75;
+const ASSIST_LABEL_COLOR: Color = Color::new(1.0, 1.0, 1.0, 0.4);
+
+const RADAR_RADIUS: f32 = 50.0;
+const RADAR_RANGE: f32 = 400.0;
+const RADAR_MARGIN: f32 = 20.0;
+const RADAR_ARROW_SIZE: f32 = 10.0;
+
+const CANCEL_RADIUS: f32 = 15.0;
+const CANCEL_SCORE: u32 = 10;
+
+const BOMB_DAMAGE: u32 = 10;
+const BOMB_INVULNERABILITY: f32 = 2.0;
+const BOMB_SHOCKWAVE_PARTICLES: usize = 32;
 
 const DIR_UP: [f32; 2] = [0.0, -1.0];
 const DIR_DOWN: [f32; 2] = [0.0, 1.0];
@@ -620,9 +5088,19 @@ const DIR_LEFT: [f32; 2] = [-1.0, 0.0];
 const DIR_RIGHT: [f32; 2] = [1.0, 0.0];
 
 impl ggez::event::EventHandler<GameError> for State {
+    // Returning true keeps the window open until Yes is picked in the dialog
+    fn quit_event(&mut self, _ctx: &mut Context) -> Result<bool, GameError> {
+        if self.quit_confirmed {
+            return Ok(false);
+        }
+
+        self.confirm_quit();
+        Ok(true)
+    }
+
     fn resize_event(&mut self, _ctx: &mut Context, w: f32, h: f32) -> Result<(), GameError> {
-        self.screen.width = w;
-        self.screen.height = h;
+        self.window.width = w;
+        self.window.height = h;
         Ok(())
     }
 
@@ -632,165 +5110,543 @@ impl ggez::event::EventHandler<GameError> for State {
         input: input::keyboard::KeyInput,
         _repeated: bool,
     ) -> Result<(), GameError> {
-        match input.keycode {
-            Some(KeyCode::Return) | Some(KeyCode::Space) if !_repeated => match self.gamestate {
-                GameState::Cinematic => {
-                    if self.story.pop().is_none() || self.story.is_empty() {
-                        self.gamestate = GameState::Combat;
-                    }
-                }
-                GameState::Paused => {
-                    if let Some(elem) = self.uis[0].front() {
-                        (elem.action)(ctx, self);
-                    }
-                }
-                _ => {}
-            },
-            Some(KeyCode::Escape) if !_repeated && self.gamestate != GameState::Paused => {
-                self.gamestate = GameState::Paused;
-                self.uis.push_front(pause_menu());
+        // Typing a name swallows every key, so letters don't restart or pause the game
+        if self.name_entry.is_some() {
+            self.name_key_fresh = !_repeated;
+            if let Some(key) = input.keycode.filter(|_| !_repeated) {
+                self.on_name_key(ctx, key);
+            }
+            return Ok(());
+        }
+
+        if let Some(binding) = self.rebinding {
+            // The held confirm key repeats, so only a fresh press counts
+            if let Some(key) = input.keycode.filter(|_| !_repeated) {
+                self.on_rebind_key(binding, key);
             }
+            return Ok(());
+        }
+
+        match input.keycode {
+            Some(KeyCode::Return) | Some(KeyCode::Space) if !_repeated => self.on_confirm(ctx),
+            Some(key) if key == self.bindings.skip && !_repeated => self.on_skip(),
+            Some(key) if key == self.bindings.bomb && !_repeated => self.bomb_queued = true,
+            Some(key) if key == self.bindings.pause && !_repeated => self.on_pause(),
             Some(KeyCode::R) if !_repeated => {
                 self.restart(ctx);
             }
-            Some(key) if self.gamestate == GameState::Paused => match key {
-                KeyCode::Down | KeyCode::Right | KeyCode::S | KeyCode::D => {
-                    if let Some(elem) = self.uis[0].pop_front() {
-                        self.uis[0].push_back(elem);
-                    }
-                }
-                KeyCode::Up | KeyCode::Left | KeyCode::W | KeyCode::A => {
-                    if let Some(elem) = self.uis[0].pop_back() {
-                        self.uis[0].push_front(elem);
-                    }
+            Some(KeyCode::F3) if !_repeated => self.debug_overlay = !self.debug_overlay,
+            Some(KeyCode::F2) if !_repeated => self.show_hitboxes = !self.show_hitboxes,
+            Some(KeyCode::F4) if !_repeated => self.radar = !self.radar,
+            Some(KeyCode::F11) if !_repeated => self.toggle_fullscreen(ctx)?,
+            Some(KeyCode::F5) if !_repeated => self.save_replay(),
+            Some(KeyCode::F6) if !_repeated => self.play_replay(ctx),
+            Some(key) if !self.uis.is_empty() => {
+                let KeyBindings {
+                    up,
+                    down,
+                    left,
+                    right,
+                    ..
+                } = self.bindings;
+
+                match key {
+                    KeyCode::Down => self.on_navigate(true),
+                    KeyCode::Up => self.on_navigate(false),
+                    KeyCode::Right => self.on_adjust(true),
+                    KeyCode::Left => self.on_adjust(false),
+                    key if key == down => self.on_navigate(true),
+                    key if key == up => self.on_navigate(false),
+                    key if key == right => self.on_adjust(true),
+                    key if key == left => self.on_adjust(false),
+                    _ => {}
                 }
-                _ => {}
-            },
+            }
             _ => {}
         }
 
         Ok(())
     }
 
-    fn update(&mut self, ctx: &mut Context) -> GameResult {
-        let curr = get_script_mod_date();
-        if curr != self.last_update {
-            self.last_update = curr;
-            self.restart(ctx);
+    fn text_input_event(&mut self, _ctx: &mut Context, character: char) -> Result<(), GameError> {
+        if std::mem::take(&mut self.name_key_fresh) {
+            self.on_name_input(character);
         }
+        Ok(())
+    }
 
-        match self.gamestate {
-            GameState::Combat => self.on_combat_update(ctx),
-            _ => Ok(()),
+    fn mouse_motion_event(
+        &mut self,
+        ctx: &mut Context,
+        x: f32,
+        y: f32,
+        _dx: f32,
+        _dy: f32,
+    ) -> Result<(), GameError> {
+        let Point2 { x, y } = self.to_logical(x, y);
+        self.on_hover(ctx, x, y);
+        Ok(())
+    }
+
+    fn mouse_button_down_event(
+        &mut self,
+        ctx: &mut Context,
+        button: input::mouse::MouseButton,
+        x: f32,
+        y: f32,
+    ) -> Result<(), GameError> {
+        let Point2 { x, y } = self.to_logical(x, y);
+        if button == input::mouse::MouseButton::Left && self.on_hover(ctx, x, y) {
+            self.on_confirm(ctx);
         }
+        Ok(())
     }
 
-    fn draw(&mut self, ctx: &mut Context) -> GameResult {
-        let mut canvas = Canvas::from_frame(ctx, Color::from_rgb(0x2b, 0x2c, 0x2f));
+    fn gamepad_button_down_event(
+        &mut self,
+        ctx: &mut Context,
+        btn: gilrs::Button,
+        _id: GamepadId,
+    ) -> Result<(), GameError> {
+        // Name entry and rebinding wait on the keyboard, the pad can't restart or pause under them
+        if self.name_entry.is_some() || self.rebinding.is_some() {
+            return Ok(());
+        }
 
-        let width = self.screen.width;
-        let height = self.screen.height;
+        let menu_open = !self.uis.is_empty();
 
-        let half_width = width * 0.5;
-        let half_height = height * 0.5;
+        match btn {
+            gilrs::Button::South => self.on_confirm(ctx),
+            gilrs::Button::North => self.on_skip(),
+            gilrs::Button::West => self.bomb_queued = true,
+            gilrs::Button::Start => self.on_pause(),
+            gilrs::Button::Select => self.restart(ctx),
+            gilrs::Button::DPadDown if menu_open => self.on_navigate(true),
+            gilrs::Button::DPadUp if menu_open => self.on_navigate(false),
+            gilrs::Button::DPadRight if menu_open => self.on_adjust(true),
+            gilrs::Button::DPadLeft if menu_open => self.on_adjust(false),
+            _ => {}
+        }
 
-        let (w, h) = (
-            self.background.width() as f32,
-            self.background.height() as f32,
-        );
+        Ok(())
+    }
 
-        canvas.draw(
-            &self.background,
-            DrawParam::default().scale([width / w, height / h]),
-        );
+    fn update(&mut self, ctx: &mut Context) -> GameResult {
+        // A replay runs on the script it was recorded with, so edits wait until it ends
+        if self.playback.is_none() && self.watcher.changed() {
+            self.reload(ctx);
+        }
 
-        if let Some(ref enemy) = self.enemy {
-            self.draw_body(&mut canvas, &enemy.body, 0.2, Color::BLACK);
-            enemy.spell.for_each_visible(|bullet| {
-                self.draw_body(&mut canvas, &bullet.body, 0.05, Color::RED);
-            });
+        self.update_music(ctx);
 
-            let healthbar = rect!(
-                ctx,
-                enemy.health.percentage() * 100.,
-                10.0,
-                (255, 0, 0, 127)
-            );
+        // Read once and capped, so the first frame after a stall or a pause doesn't jump ahead
+        let frame_time = ctx.time.delta().as_secs_f32().min(MAX_FRAME_TIME);
 
-            draw_at!(
-                canvas,
-                &healthbar,
-                (enemy.body.position.x - 50.0, enemy.body.position.y - 90.0)
-            );
+        if self.gamestate != GameState::Paused {
+            let scroll = self.script.as_ref().map_or(0.0, |script| script.bg_scroll);
+            let dt = frame_time * self.time_scale;
+            self.bg_offset = (self.bg_offset + scroll * dt).rem_euclid(self.screen.height);
         }
 
-        if let Some(ref player) = self.player {
-            self.draw_body(&mut canvas, &player.body, 0.12, Color::WHITE);
-            player.spell.for_each_visible(|bullet| {
-                self.draw_body(&mut canvas, &bullet.body, 0.05, Color::CYAN);
-            });
-        }
+        // Logic runs in fixed steps; a long stall drops the backlog instead of catching up on it
+        self.step_time += frame_time;
+        let mut steps = 0;
 
-        self.particles.iter().for_each(|particle| {
-            self.draw_body(&mut canvas, &particle.bullet.body, 0.05, Color::MAGENTA);
-        });
+        while self.step_time >= FIXED_DT {
+            if steps == MAX_STEPS_PER_FRAME {
+                self.step_time = 0.0;
+                break;
+            }
 
-        self.texts
-            .iter()
-            .for_each(|text| draw_at!(canvas, text, (half_width, half_height)));
+            self.step_time -= FIXED_DT;
+            steps += 1;
 
-        if let Some(line) = self.story.last() {
-            draw_at!(canvas, &line.text, (half_width, half_height), line.color);
-            draw_at!(
-                canvas,
-                &line.sprite.image,
-                (width * 0.5 + line.pos.x, height * 0.5 + line.pos.y),
-                line.sprite.color
-            );
+            match self.gamestate {
+                GameState::Combat => self.on_combat_update(ctx)?,
+                GameState::Cinematic => self.on_cinematic_update(ctx),
+                _ => {}
+            }
         }
 
-        // TODO: limited pauses, with breaking effect after unpausing
-        if self.gamestate == GameState::Paused {
-            let background = rect!(ctx, width, height, (0, 0, 0, 127));
-            draw_at!(canvas, &background, (0.0, 0.0));
-        }
+        Ok(())
+    }
 
-        self.uis.iter().for_each(|ui| {
-            if let Some(elem) = ui.front() {
-                let Point2 { x, y } = elem.pos;
-                draw_at!(
-                    canvas,
-                    &elem.img,
-                    (half_width + x, half_height + y),
-                    elem.select_color
-                );
-            }
+    fn draw(&mut self, ctx: &mut Context) -> GameResult {
+        let clear = self.clear_color;
+        let mut canvas = Canvas::from_frame(ctx, clear);
 
-            ui.iter().skip(1).for_each(|elem| {
-                let Point2 { x, y } = elem.pos;
-                draw_at!(
-                    canvas,
-                    &elem.img,
-                    (half_width + x, half_height + y),
-                    elem.color
-                );
-            });
-        });
+        // The frozen frame is drawn offscreen, then blurred behind the menu
+        match self.blur_shader {
+            Some(ref shader) if self.blurs_pause() => {
+                let frame = self.scene.image(ctx);
+                let mut scene = Canvas::from_image(ctx, frame.clone(), clear);
+                self.draw_layers(ctx, &mut scene, &Layer::ALL[..Layer::ALL.len() - 1]);
+                scene.finish(ctx)?;
+
+                canvas.set_shader(shader);
+                canvas.draw(&frame, DrawParam::default());
+                canvas.set_default_shader();
+                self.draw_layers(ctx, &mut canvas, &[Layer::Overlay]);
+            }
+            _ => self.draw_layers(ctx, &mut canvas, &Layer::ALL),
+        }
 
         canvas.finish(ctx)
     }
 }
 
-fn load_image(ctx: &Context, path: &str) -> Image {
-    Image::from_path(ctx, path).unwrap_or(Image::from_color(ctx, 1, 1, None))
+fn load_sound(ctx: &Context, path: &str) -> Option<audio::Source> {
+    audio::Source::new(ctx, path).ok()
+}
+
+fn load_background(ctx: &Context, assets: &mut AssetCache, name: &str) -> Image {
+    assets.get_or_load(ctx, format!("/{}/", name).as_str())
+}
+
+fn load_music(ctx: &Context, name: &str) -> Option<audio::Source> {
+    load_sound(ctx, format!("/{}", name).as_str()).map(|mut music| {
+        music.set_repeat(true);
+        music
+    })
 }
 
 fn main() -> GameResult {
-    let (ctx, event_loop) = ContextBuilder::new("Touhou Engine", "Rontero")
+    let mut args = std::env::args().skip(1);
+    let first = args.next();
+
+    // `cargo run -- --check script.th` only parses the script, without opening a window
+    if first.as_deref() == Some("--check") {
+        let path = args.next().unwrap_or_else(|| SCRIPT_PATH.to_owned());
+        std::process::exit(if check_script(&path) { 0 } else { 1 });
+    }
+
+    // `cargo run -- levels/other.th` plays another script, script.th otherwise
+    let script_path = first.unwrap_or_else(|| SCRIPT_PATH.to_owned());
+
+    let (ctx, event_loop) = ContextBuilder::new(GAME_TITLE, "Rontero")
         .add_resource_path(std::path::PathBuf::from("./assets"))
-        .default_conf(conf::Conf::new())
+        .default_conf(window_conf(&script_path))
         .build()?;
 
-    let state = State::new(&ctx);
+    let state = State::new(
+        &ctx,
+        Difficulty::default(),
+        AssetCache::default(),
+        script_path,
+    );
     event::run(ctx, event_loop, state);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headless_bullet(speed: f32) -> Bullet {
+        let sprite = Sprite {
+            image: None,
+            color: Color::WHITE,
+            scale: 1.0,
+        };
+        Bullet::new(&sprite, DIR_UP, speed)
+    }
+
+    #[test]
+    fn acceleration_approaches_max_speed() {
+        let mut speed = 50.0;
+        for _ in 0..1000 {
+            let next = accelerate(speed, 120.0, Some(400.0), FIXED_DT);
+            assert!(next >= speed && next <= 400.0);
+            speed = next;
+        }
+        assert_eq!(speed, 400.0);
+    }
+
+    #[test]
+    fn deceleration_stops_at_zero() {
+        let mut speed = 300.0;
+        for _ in 0..1000 {
+            speed = accelerate(speed, -500.0, None, FIXED_DT);
+            assert!(speed >= 0.0);
+        }
+        assert_eq!(speed, 0.0);
+    }
+
+    #[test]
+    fn parses_hex_colors() {
+        let orange = Color::from_rgb(0xff, 0x88, 0x00);
+        assert_eq!(parse_color("#ff8800"), Ok(orange));
+        assert_eq!(parse_color("#f80"), Ok(orange));
+        assert_eq!(parse_color("#Ff8800"), Ok(orange));
+        assert_eq!(parse_color("#F80"), Ok(orange));
+        assert_eq!(
+            parse_color("#ff880080"),
+            Ok(Color::from_rgba(0xff, 0x88, 0x00, 0x80))
+        );
+        assert_eq!(
+            parse_color("#f808"),
+            Ok(Color::from_rgba(0xff, 0x88, 0x00, 0x88))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_colors() {
+        for text in [
+            "", "#", "ff8800", "#ff880", "#ff8800a", "#gg8800", "#ff 800", "#+f8800", "#fé8800",
+            "orange",
+        ] {
+            assert!(parse_color(text).is_err(), "{text} parsed");
+        }
+    }
+
+    #[test]
+    fn parses_named_colors() {
+        assert_eq!(parse_color("white"), Ok(Color::WHITE));
+        assert_eq!(parse_color("Black"), Ok(Color::BLACK));
+        assert_eq!(parse_color("YELLOW"), Ok(Color::YELLOW));
+    }
+
+    #[test]
+    fn resisted_damage_adds_up() {
+        let mut health = Health::new(10);
+        health.set_resistance(DamageType::Normal, 0.5);
+
+        assert_eq!(health.take_damage(1, DamageType::Normal), Some(0));
+        assert_eq!(health.take_damage(1, DamageType::Normal), Some(1));
+        assert_eq!(health.take_damage(3, DamageType::Normal), Some(1));
+        assert_eq!(health.take_damage(1, DamageType::Normal), Some(1));
+        assert_eq!(health.health, 7);
+    }
+
+    #[test]
+    fn strips_full_line_comments() {
+        assert_eq!(
+            strip_comments("# the boss\nreimu's health is 200."),
+            "\nreimu's health is 200."
+        );
+    }
+
+    #[test]
+    fn strips_inline_comments() {
+        assert_eq!(
+            strip_comments("sakuya's lives is 3. # tries in total"),
+            "sakuya's lives is 3."
+        );
+    }
+
+    #[test]
+    fn keeps_hashes_inside_quotes() {
+        assert_eq!(
+            strip_comments("the clear_color is \"#2b2c2f\". # dark grey"),
+            "the clear_color is \"#2b2c2f\"."
+        );
+    }
+
+    #[test]
+    fn keeps_blank_and_comment_only_lines_in_place() {
+        let script = "reimu's health is 200.\n\n   # spacer\n#\nmarisa's health is 100.";
+
+        assert_eq!(
+            strip_comments(script),
+            "reimu's health is 200.\n\n\n\nmarisa's health is 100."
+        );
+    }
+
+    #[test]
+    fn reports_misspelled_fields() {
+        let script = "reimu's health is 200.\nthe helth of sakuya is 1, and marisa's sped is 2.\n\
+                      the background is \"bg.png\", and the backgroud is \"bg.png\".";
+
+        assert_eq!(
+            unknown_fields(script),
+            vec![
+                (2, "sakuya".to_owned(), "helth".to_owned()),
+                (2, "marisa".to_owned(), "sped".to_owned()),
+                (3, "script".to_owned(), "backgroud".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn bundled_script_has_no_unknown_fields() {
+        let script = strip_comments(include_str!("../script.th"));
+
+        assert_eq!(unknown_fields(&script), vec![]);
+    }
+
+    #[test]
+    fn sparse_straight_spells_skip_every_other_volley() {
+        let center = Point2 { x: 0.0, y: 0.0 };
+        let mut spell = Spell::new(headless_bullet(100.0), 4, 0.0, SpellPattern::Straight);
+        spell.sparse = true;
+
+        let fired: Vec<_> = (0..4)
+            .map(|_| spell.spawn(FIXED_DT, &center, None))
+            .collect();
+        assert_eq!(fired, [false, true, false, true]);
+    }
+
+    #[test]
+    fn replays_survive_a_trailing_blank_script_line() {
+        let path = std::env::temp_dir().join("touhouggez_replay_test.txt");
+        let path = path.to_str().unwrap();
+        let replay = Replay {
+            difficulty: Difficulty::Hard,
+            seed: 42,
+            assist: true,
+            script: strip_comments("reimu's health is 200.\n\n"),
+            frames: vec![InputFrame {
+                dir: [1.0, -1.0],
+                shoot: true,
+                resumed: true,
+                ..InputFrame::default()
+            }],
+        };
+
+        replay.save(path).unwrap();
+        let loaded = Replay::load(path).unwrap();
+        std::fs::remove_file(path).ok();
+
+        assert_eq!(loaded.seed, 42);
+        assert!(loaded.assist);
+        assert_eq!(loaded.script, replay.script);
+        assert_eq!(loaded.frames.len(), 1);
+        assert_eq!(loaded.frames[0].dir, [1.0, -1.0]);
+        assert!(loaded.frames[0].shoot && !loaded.frames[0].bomb);
+        assert!(loaded.frames[0].resumed && !loaded.frames[0].assist);
+    }
+
+    #[test]
+    fn waving_bullets_sway_across_their_heading() {
+        let screen = Screen {
+            width: SCREEN_WIDTH,
+            height: SCREEN_HEIGHT,
+        };
+        let start = Point2 { x: 100.0, y: 100.0 };
+        let heading = Point2 { x: 1.0, y: 1.0 }.normalize();
+        let (amplitude, frequency) = (20.0, 6.0);
+
+        let mut bullet = headless_bullet(100.0);
+        bullet.body.rigidbody.position = start;
+        bullet.body.rigidbody.direction = heading;
+        bullet.wave = Some(BulletWave {
+            amplitude,
+            frequency,
+        });
+
+        let mut last_sway = 0.0;
+        for step in 1..=240 {
+            bullet.update(FIXED_DT, &screen);
+
+            let straight = start.add(heading.scale(100.0 * FIXED_DT * step as f32));
+            let offset = bullet.body.position().sub(straight);
+            assert!(offset.dot(heading).abs() < 1e-2);
+            assert!(offset.length() <= amplitude + 1e-2);
+
+            // A step never moves the bullet across further than the sway's top speed allows
+            let sway = offset.dot(Point2 {
+                x: -heading.y,
+                y: heading.x,
+            });
+            assert!((sway - last_sway).abs() <= amplitude * frequency * FIXED_DT + 1e-3);
+            last_sway = sway;
+        }
+    }
+
+    #[test]
+    fn burst_particles_all_retire_after_their_ttl() {
+        let screen = Screen {
+            width: SCREEN_WIDTH,
+            height: SCREEN_HEIGHT,
+        };
+        let sprite = headless_bullet(0.0).body.sprite;
+        let center = [SCREEN_WIDTH * 0.5, SCREEN_HEIGHT * 0.5];
+        let ttl = 0.5;
+        let steps = (ttl / FIXED_DT) as usize;
+
+        for count in [0, 1, DEATH_PARTICLES * 4 + 1] {
+            let mut particles = burst(&sprite, center, (count, ttl, 50.0), 0.3);
+            assert_eq!(particles.len(), count);
+
+            let step = |particles: &mut Vec<Particle>| {
+                particles.retain_mut(|particle| {
+                    particle.advance(FIXED_DT, &screen);
+                    particle.bullet.is_visible
+                })
+            };
+
+            for _ in 0..steps - 1 {
+                step(&mut particles);
+            }
+            assert_eq!(particles.len(), count);
+
+            for _ in 0..2 {
+                step(&mut particles);
+            }
+            assert!(particles.is_empty());
+        }
+    }
+
+    #[test]
+    fn viewport_fits_the_playfield_into_any_window() {
+        let screen = Screen {
+            width: SCREEN_WIDTH,
+            height: SCREEN_HEIGHT,
+        };
+        let window = |width, height| Screen { width, height };
+
+        for (window, expected) in [
+            // Wider shows more to the sides, taller shows more above and below
+            (window(1600.0, 600.0), Rect::new(-400.0, 0.0, 1600.0, 600.0)),
+            (window(800.0, 1200.0), Rect::new(0.0, -300.0, 800.0, 1200.0)),
+            (window(1600.0, 1200.0), Rect::new(0.0, 0.0, 800.0, 600.0)),
+        ] {
+            let viewport = screen.viewport(&window);
+            assert_eq!(viewport, expected);
+
+            // Where the playfield's corner and center land in the window, mapped back again
+            for logical in [[0.0, 0.0], [SCREEN_WIDTH * 0.5, SCREEN_HEIGHT * 0.5]] {
+                let x = (logical[0] - viewport.x) * window.width / viewport.w;
+                let y = (logical[1] - viewport.y) * window.height / viewport.h;
+                let Point2 { x, y } = screen.to_logical(&window, x, y);
+                assert!((x - logical[0]).abs() < 1e-3 && (y - logical[1]).abs() < 1e-3);
+            }
+        }
+
+        let Point2 { x, y } = screen.to_logical(&window(1600.0, 1200.0), 1600.0, 1200.0);
+        assert_eq!([x, y], [SCREEN_WIDTH, SCREEN_HEIGHT]);
+    }
+
+    #[test]
+    fn bullets_leaving_the_screen_are_reused() {
+        let screen = Screen {
+            width: SCREEN_WIDTH,
+            height: SCREEN_HEIGHT,
+        };
+        let center = Point2 {
+            x: SCREEN_WIDTH * 0.5,
+            y: SCREEN_HEIGHT * 0.5,
+        };
+        let mut spell = Spell::new(headless_bullet(100.0), 1, 0.0, SpellPattern::Straight);
+
+        // The pool holds a single bullet, so every edge has to hand it back before the next shot
+        for direction in [DIR_UP, DIR_DOWN, DIR_LEFT, DIR_RIGHT] {
+            spell.direction = Point2::from(direction);
+            assert!(spell.spawn(FIXED_DT, &center, None));
+            assert!(!spell.spawn(FIXED_DT, &center, None));
+
+            for _ in 0..100 {
+                spell.for_each_visible_mut(|bullet| bullet.update(0.1, &screen));
+            }
+
+            let bullet = &spell.bullets[0];
+            let Point2 { x, y } = bullet.body.position();
+            let edge = match direction {
+                DIR_UP => y < 0.0,
+                DIR_DOWN => y > screen.height,
+                DIR_LEFT => x < 0.0,
+                _ => x > screen.width,
+            };
+            assert!(edge && !bullet.is_visible);
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use ggez::mint::Point2;
+use std::collections::HashMap;
+
+#[derive(Clone)]
+pub struct Rigidbody {
+    pub position: Point2<f32>,
+    pub direction: Point2<f32>,
+    pub speed: f32,
+}
+
+// Buckets ids by cell so collision checks only look at what's nearby, rebuild it every frame
+pub struct Grid {
+    cell_size: f32,
+    cells: HashMap<(i32, i32), Vec<usize>>,
+}
+
+pub trait Vector: Copy {
+    fn add(self, other: Self) -> Self;
+    fn sub(self, other: Self) -> Self;
+    fn scale(self, factor: f32) -> Self;
+    fn dot(self, other: Self) -> f32;
+    fn rotate(self, angle: f32) -> Self;
+
+    fn length(self) -> f32 {
+        self.dot(self).sqrt()
+    }
+
+    fn distance(self, other: Self) -> f32 {
+        self.sub(other).length()
+    }
+
+    // A zero vector has no direction, so it stays zero instead of turning into NaN
+    fn normalize(self) -> Self {
+        let length = self.length();
+        if length > 0.0 {
+            self.scale(1.0 / length)
+        } else {
+            self
+        }
+    }
+}
+
+pub trait Movable {
+    fn rigidbody(&self) -> &Rigidbody;
+    fn rigidbody_mut(&mut self) -> &mut Rigidbody;
+
+    fn position(&self) -> Point2<f32> {
+        self.rigidbody().position
+    }
+
+    fn velocity(&self) -> Point2<f32> {
+        let Rigidbody {
+            direction, speed, ..
+        } = self.rigidbody();
+
+        direction.scale(*speed)
+    }
+
+    fn move_by(&mut self, offset: Point2<f32>) {
+        let position = &mut self.rigidbody_mut().position;
+        *position = position.add(offset);
+    }
+}
+
+impl Rigidbody {
+    pub fn new(position: [f32; 2], direction: [f32; 2], speed: f32) -> Self {
+        Self {
+            position: Point2::from(position),
+            direction: Point2::from(direction),
+            speed,
+        }
+    }
+}
+
+impl Vector for Point2<f32> {
+    fn add(self, other: Self) -> Self {
+        Point2 {
+            x: self.x + other.x,
+            y: self.y + other.y,
+        }
+    }
+
+    fn sub(self, other: Self) -> Self {
+        self.add(other.scale(-1.0))
+    }
+
+    fn scale(self, factor: f32) -> Self {
+        Point2 {
+            x: self.x * factor,
+            y: self.y * factor,
+        }
+    }
+
+    fn dot(self, other: Self) -> f32 {
+        self.x * other.x + self.y * other.y
+    }
+
+    fn rotate(self, angle: f32) -> Self {
+        let (sin, cos) = angle.sin_cos();
+        Point2 {
+            x: self.x * cos - self.y * sin,
+            y: self.x * sin + self.y * cos,
+        }
+    }
+}
+
+impl Grid {
+    pub fn new(cell_size: f32) -> Self {
+        Self {
+            cell_size,
+            cells: HashMap::new(),
+        }
+    }
+
+    fn cell(&self, position: Point2<f32>) -> (i32, i32) {
+        (
+            (position.x / self.cell_size).floor() as i32,
+            (position.y / self.cell_size).floor() as i32,
+        )
+    }
+
+    pub fn insert(&mut self, id: usize, position: Point2<f32>) {
+        let cell = self.cell(position);
+        self.cells.entry(cell).or_default().push(id);
+    }
+
+    // Candidates only, callers still need their own distance check
+    pub fn neighbors(
+        &self,
+        position: Point2<f32>,
+        radius: f32,
+    ) -> impl Iterator<Item = usize> + '_ {
+        let (min_x, min_y) = self.cell(position.sub(Point2 {
+            x: radius,
+            y: radius,
+        }));
+        let (max_x, max_y) = self.cell(position.add(Point2 {
+            x: radius,
+            y: radius,
+        }));
+
+        (min_x..=max_x)
+            .flat_map(move |x| (min_y..=max_y).map(move |y| (x, y)))
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Dot(Rigidbody);
+
+    impl Movable for Dot {
+        fn rigidbody(&self) -> &Rigidbody {
+            &self.0
+        }
+
+        fn rigidbody_mut(&mut self) -> &mut Rigidbody {
+            &mut self.0
+        }
+    }
+
+    #[test]
+    fn move_by_adds_up_offsets() {
+        let mut dot = Dot(Rigidbody::new([10.0, 20.0], [0.0, 0.0], 0.0));
+
+        dot.move_by(Point2 { x: 5.0, y: -2.0 });
+        dot.move_by(Point2 { x: -1.5, y: 4.0 });
+        dot.move_by(Point2 { x: 0.5, y: 0.0 });
+
+        assert_eq!(dot.position(), Point2 { x: 14.0, y: 22.0 });
+    }
+
+    #[test]
+    fn normalizing_zero_stays_zero() {
+        let zero = Point2 { x: 0.0, y: 0.0 }.normalize();
+        assert_eq!(zero, Point2 { x: 0.0, y: 0.0 });
+    }
+
+    #[test]
+    fn rotating_a_quarter_turn() {
+        let turned = Point2 { x: 1.0, y: 0.0 }.rotate(std::f32::consts::FRAC_PI_2);
+        assert!(turned.distance(Point2 { x: 0.0, y: 1.0 }) < 1e-6);
+    }
+
+    // The cancel pass relies on this, so it has to find exactly what checking every pair would
+    #[test]
+    fn grid_matches_brute_force() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let radius = 15.0;
+        let mut rng = StdRng::seed_from_u64(7);
+        let points: Vec<_> = (0..500)
+            .map(|_| Point2 {
+                x: rng.gen_range(-50.0..850.0),
+                y: rng.gen_range(-50.0..650.0),
+            })
+            .collect();
+
+        let mut grid = Grid::new(radius * 2.0);
+        for (i, &point) in points.iter().enumerate() {
+            grid.insert(i, point);
+        }
+
+        for &point in points.iter() {
+            let mut found: Vec<_> = grid
+                .neighbors(point, radius)
+                .filter(|&j| points[j].distance(point) < radius)
+                .collect();
+            found.sort_unstable();
+
+            let expected: Vec<_> = (0..points.len())
+                .filter(|&j| points[j].distance(point) < radius)
+                .collect();
+            assert_eq!(found, expected);
+        }
+    }
+}